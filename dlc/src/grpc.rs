@@ -0,0 +1,246 @@
+/*
+ * Copyright 2024 Akash Rawal
+ *
+ * This file is part of Disposables.
+ *
+ * Disposables is free software: you can redistribute it and/or modify it under 
+ * the terms of the GNU General Public License as published by the 
+ * Free Software Foundation, either version 3 of the License, or 
+ * (at your option) any later version.
+ * 
+ * Disposables is distributed in the hope that it will be useful, 
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of 
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. 
+ * See the GNU General Public License for more details.
+ * 
+ * You should have received a copy of the GNU General Public License 
+ * along with Disposables. If not, see <https://www.gnu.org/licenses/>. 
+ */
+//Minimal client for the gRPC health checking protocol
+//(grpc.health.v1.Health/Check), over HTTP/2 without TLS
+
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::FutureExt;
+use http::{HeaderMap, Request, StatusCode};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+const CHECK_PATH: &str = "/grpc.health.v1.Health/Check";
+//HealthCheckResponse.ServingStatus.SERVING
+const SERVING: u64 = 1;
+
+fn status_name(status: u64) -> &'static str {
+    match status {
+        0 => "UNKNOWN",
+        1 => "SERVING",
+        2 => "NOT_SERVING",
+        3 => "SERVICE_UNKNOWN",
+        _ => "an unrecognized status",
+    }
+}
+
+fn encode_varint(mut value: u64, buf: &mut BytesMut) {
+    while value >= 0x80 {
+        buf.put_u8(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.put_u8(value as u8);
+}
+
+fn decode_varint(buf: &mut &[u8]) -> Option<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = buf.split_first()?;
+        *buf = rest;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+//Length-prefixed message containing HealthCheckRequest { service }
+fn encode_request(service: &str) -> Bytes {
+    let mut message = BytesMut::new();
+    if !service.is_empty() {
+        //Field 1, length delimited
+        message.put_u8(0x0a);
+        encode_varint(service.len() as u64, &mut message);
+        message.put_slice(service.as_bytes());
+    }
+    let mut frame = BytesMut::new();
+    //Not compressed
+    frame.put_u8(0);
+    frame.put_u32(message.len() as u32);
+    frame.put(message);
+    frame.freeze()
+}
+
+//Returns the status from a length-prefixed HealthCheckResponse
+fn decode_response(frame: &[u8]) -> Result<u64, String> {
+    let invalid = || "invalid response message".to_owned();
+    let (&compressed, rest) = frame.split_first().ok_or_else(invalid)?;
+    if compressed != 0 {
+        return Err("compressed responses are not supported".to_owned());
+    }
+    let (len, rest) = rest.split_first_chunk::<4>().ok_or_else(invalid)?;
+    let len = u32::from_be_bytes(*len) as usize;
+    let mut message = rest.get(..len).ok_or_else(invalid)?;
+
+    //Fields other than status are skipped
+    let mut status = 0;
+    while !message.is_empty() {
+        let key = decode_varint(&mut message).ok_or_else(invalid)?;
+        let skip = match key & 7 {
+            0 => {
+                let value = decode_varint(&mut message).ok_or_else(invalid)?;
+                if key >> 3 == 1 {
+                    status = value;
+                }
+                0
+            },
+            1 => 8,
+            2 => decode_varint(&mut message).ok_or_else(invalid)? as usize,
+            5 => 4,
+            _ => return Err(invalid()),
+        };
+        message = message.get(skip..).ok_or_else(invalid)?;
+    }
+    Ok(status)
+}
+
+//Fails when the headers or trailers carry a status other than OK
+fn check_grpc_status(headers: &HeaderMap) -> Result<(), String> {
+    match headers.get("grpc-status").map(|v| v.as_bytes()) {
+        None | Some(b"0") => Ok(()),
+        Some(status) => {
+            let message = headers.get("grpc-message")
+                .and_then(|m| m.to_str().ok())
+                .unwrap_or_default();
+            Err(format!("gRPC status {}: {message}",
+                String::from_utf8_lossy(status)))
+        },
+    }
+}
+
+fn h2_error(e: h2::Error) -> String {
+    format!("HTTP/2 error: {e}")
+}
+
+//Calls the health service over the stream, succeeds if `service` is
+//SERVING. The server's overall health is checked when `service` is empty.
+pub async fn health_check(stream: impl AsyncRead + AsyncWrite + Unpin,
+    authority: &str, service: &str) -> Result<(), String> {
+    let (client, connection) = h2::client::handshake(stream).await
+        .map_err(h2_error)?;
+
+    let call = async {
+        let mut client = client.ready().await.map_err(h2_error)?;
+        let request = Request::post(format!("http://{authority}{CHECK_PATH}"))
+            .header("content-type", "application/grpc")
+            .header("te", "trailers")
+            .body(())
+            .map_err(|e| format!("Invalid request: {e}"))?;
+        let (response, mut send) = client.send_request(request, false)
+            .map_err(h2_error)?;
+        send.send_data(encode_request(service), true).map_err(h2_error)?;
+
+        let response = response.await.map_err(h2_error)?;
+        if response.status() != StatusCode::OK {
+            return Err(format!("HTTP status {}", response.status()));
+        }
+        check_grpc_status(response.headers())?;
+
+        let mut body = response.into_body();
+        let mut data = BytesMut::new();
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk.map_err(h2_error)?;
+            let _ = body.flow_control().release_capacity(chunk.len());
+            data.put(chunk);
+        }
+        if let Some(trailers) = body.trailers().await.map_err(h2_error)? {
+            check_grpc_status(&trailers)?;
+        }
+
+        match decode_response(&data)? {
+            SERVING => Ok(()),
+            status => Err(format!("service is {}", status_name(status))),
+        }
+    }.fuse();
+    //The connection has to be polled for the call to make progress
+    let connection = connection.fuse();
+    futures::pin_mut!(call, connection);
+
+    futures::select!{
+        res = call => res,
+        res = connection => Err(match res {
+            Ok(()) => "Connection closed".to_owned(),
+            Err(e) => h2_error(e),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::io::DuplexStream;
+
+    use super::*;
+
+    //Answers a single health check with the given status
+    async fn serve(stream: DuplexStream, service: &str, status: u8) {
+        let mut conn = h2::server::handshake(stream).await.unwrap();
+        let (request, mut respond) = conn.accept().await.unwrap().unwrap();
+        assert_eq!(request.uri().path(), CHECK_PATH);
+        let mut body = request.into_body();
+        let data = body.data().await.unwrap().unwrap();
+        assert_eq!(data, encode_request(service));
+
+        let response = http::Response::builder()
+            .header("content-type", "application/grpc")
+            .body(()).unwrap();
+        let mut send = respond.send_response(response, false).unwrap();
+        send.send_data(Bytes::from(vec![0, 0, 0, 0, 2, 0x08, status]), false)
+            .unwrap();
+        let mut trailers = HeaderMap::new();
+        trailers.insert("grpc-status", "0".parse().unwrap());
+        send.send_trailers(trailers).unwrap();
+
+        //Keep the connection going till the client is done
+        while conn.accept().await.is_some() {}
+    }
+
+    #[test]
+    fn request_for_server_health_is_empty() {
+        assert_eq!(encode_request(""), Bytes::from_static(&[0, 0, 0, 0, 0]));
+        assert_eq!(encode_request("db"),
+            Bytes::from_static(&[0, 0, 0, 0, 4, 0x0a, 2, b'd', b'b']));
+    }
+
+    #[test]
+    fn unknown_fields_are_skipped() {
+        //Field 2 (varint 300), field 3 ("ab"), field 1 (2)
+        let frame = [0, 0, 0, 0, 9, 0x10, 0xac, 0x02, 0x1a, 2, b'a', b'b',
+            0x08, 2];
+        assert_eq!(decode_response(&frame), Ok(2));
+        assert_eq!(decode_response(&[0, 0, 0, 0, 0]), Ok(0));
+        assert!(decode_response(&[0, 0, 0, 0, 2, 0x08]).is_err());
+    }
+
+    #[tokio::test]
+    async fn serving_service_passes_health_check() {
+        let (client, server) = tokio::io::duplex(4096);
+        let (res, ()) = futures::join!(
+            health_check(client, "localhost:50051", "db"),
+            serve(server, "db", 1));
+        assert_eq!(res, Ok(()));
+    }
+
+    #[tokio::test]
+    async fn not_serving_service_fails_health_check() {
+        let (client, server) = tokio::io::duplex(4096);
+        let (res, ()) = futures::join!(
+            health_check(client, "localhost:50051", ""),
+            serve(server, "", 2));
+        assert_eq!(res, Err("service is NOT_SERVING".to_owned()));
+    }
+}
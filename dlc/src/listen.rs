@@ -0,0 +1,150 @@
+/*
+ * Copyright 2024 Akash Rawal
+ *
+ * This file is part of Disposables.
+ *
+ * Disposables is free software: you can redistribute it and/or modify it under 
+ * the terms of the GNU General Public License as published by the 
+ * Free Software Foundation, either version 3 of the License, or 
+ * (at your option) any later version.
+ * 
+ * Disposables is distributed in the hope that it will be useful, 
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of 
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. 
+ * See the GNU General Public License for more details.
+ * 
+ * You should have received a copy of the GNU General Public License 
+ * along with Disposables. If not, see <https://www.gnu.org/licenses/>. 
+ */
+//Detection of TCP ports listened on by a process tree, using /proc
+
+use std::collections::{HashMap, HashSet};
+use std::io::ErrorKind;
+
+//State of a listening socket in /proc/net/tcp
+const TCP_LISTEN: &str = "0A";
+
+//Returns the parent PID from the contents of /proc/<pid>/stat.
+fn parse_ppid(stat: &str) -> Option<u32> {
+    //The command name may contain spaces and parentheses
+    let (_, rest) = stat.rsplit_once(')')?;
+    rest.split_whitespace().nth(1)?.parse().ok()
+}
+
+//Returns the port and inode of listening sockets from the contents of
+//a /proc/net/tcp or /proc/net/tcp6 table.
+fn parse_listening(table: &str) -> Vec<(u16, u64)> {
+    table.lines().skip(1)
+        .filter_map(|line| {
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            if fields.get(3) != Some(&TCP_LISTEN) {
+                return None;
+            }
+            let (_, port) = fields.get(1)?.rsplit_once(':')?;
+            let port = u16::from_str_radix(port, 16).ok()?;
+            let inode = fields.get(9)?.parse().ok()?;
+            Some((port, inode))
+        })
+        .collect()
+}
+
+//Returns the given process and all its descendants.
+fn process_tree(root: u32) -> std::io::Result<HashSet<u32>> {
+    let mut children = HashMap::<u32, Vec<u32>>::new();
+    for entry in std::fs::read_dir("/proc")? {
+        let Some(pid) = entry?.file_name().to_str()
+            .and_then(|name| name.parse::<u32>().ok()) else {
+            continue;
+        };
+        //Processes can exit while being listed
+        let Ok(stat) = std::fs::read_to_string(format!("/proc/{pid}/stat"))
+        else {
+            continue;
+        };
+        if let Some(ppid) = parse_ppid(&stat) {
+            children.entry(ppid).or_default().push(pid);
+        }
+    }
+
+    let mut tree = HashSet::new();
+    let mut pending = vec![root];
+    while let Some(pid) = pending.pop() {
+        if tree.insert(pid) {
+            pending.extend(children.get(&pid).into_iter().flatten());
+        }
+    }
+    Ok(tree)
+}
+
+//Returns the inodes of the sockets the given processes have open.
+fn socket_inodes(pids: &HashSet<u32>) -> HashSet<u64> {
+    let mut inodes = HashSet::new();
+    for pid in pids {
+        let Ok(entries) = std::fs::read_dir(format!("/proc/{pid}/fd")) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let Ok(target) = std::fs::read_link(entry.path()) else {
+                continue;
+            };
+            let inode = target.to_str()
+                .and_then(|t| t.strip_prefix("socket:["))
+                .and_then(|t| t.strip_suffix(']'))
+                .and_then(|t| t.parse::<u64>().ok());
+            inodes.extend(inode);
+        }
+    }
+    inodes
+}
+
+//Returns the TCP ports the given process and its descendants listen on,
+//in ascending order.
+pub fn ports(root: u32) -> std::io::Result<Vec<u16>> {
+    let inodes = socket_inodes(&process_tree(root)?);
+    let mut ports = Vec::new();
+    for table in ["/proc/net/tcp", "/proc/net/tcp6"] {
+        let content = match std::fs::read_to_string(table) {
+            Ok(content) => content,
+            //IPv6 may be disabled
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        ports.extend(parse_listening(&content).into_iter()
+            .filter(|(_, inode)| inodes.contains(inode))
+            .map(|(port, _)| port));
+    }
+    ports.sort();
+    ports.dedup();
+    Ok(ports)
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::TcpListener;
+
+    use super::*;
+
+    #[test]
+    fn ppid_is_parsed_from_stat() {
+        let stat = "1234 (my (odd) cmd) S 42 1234 1234 0 -1 4194560";
+        assert_eq!(parse_ppid(stat), Some(42));
+    }
+
+    #[test]
+    fn only_listening_sockets_are_parsed() {
+        let table = "  sl  local_address rem_address   st tx_queue rx_queue \
+            tr tm->when retrnsmt   uid  timeout inode
+   0: 00000000:1F90 00000000:0000 0A 00000000:00000000 00:00000000 \
+            00000000     0        0 31337 1 0000000000000000 100 0 0 10 0
+   1: 0100007F:C350 0100007F:1F90 01 00000000:00000000 00:00000000 \
+            00000000     0        0 31338 1 0000000000000000 20 4 30 10 -1";
+        assert_eq!(parse_listening(table), vec![(8080, 31337)]);
+    }
+
+    #[test]
+    fn ports_of_own_process_are_found() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        assert!(ports(std::process::id()).unwrap().contains(&port));
+    }
+}
@@ -0,0 +1,106 @@
+/*
+ * Copyright 2024 Akash Rawal
+ *
+ * This file is part of Disposables.
+ *
+ * Disposables is free software: you can redistribute it and/or modify it under 
+ * the terms of the GNU General Public License as published by the 
+ * Free Software Foundation, either version 3 of the License, or 
+ * (at your option) any later version.
+ * 
+ * Disposables is distributed in the hope that it will be useful, 
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of 
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. 
+ * See the GNU General Public License for more details.
+ * 
+ * You should have received a copy of the GNU General Public License 
+ * along with Disposables. If not, see <https://www.gnu.org/licenses/>. 
+ */
+//Liveness monitoring after the container is ready
+
+use std::time::Duration;
+
+use disposables_protocol::{V1Event, V1WaitCondition};
+use tokio::sync::mpsc::Sender;
+
+use crate::Context;
+use crate::probe;
+use crate::ready::ReadySignal;
+
+async fn check(ctx: &Context) -> Result<(), String> {
+    let connect_timeout = ctx.setup.probe_connect_timeout();
+    let command_timeout = ctx.setup.command_timeout();
+    for condition in &ctx.setup.liveness {
+        match condition {
+            V1WaitCondition::Port(port) =>
+                probe::port(*port, connect_timeout).await?,
+            V1WaitCondition::Udp { port, request, reply } =>
+                probe::udp(*port, request, reply, connect_timeout).await?,
+            V1WaitCondition::Tls { port, sni, ca_file } =>
+                probe::tls(*port, sni.as_deref(), ca_file.as_deref(),
+                    connect_timeout).await.map(|_| ())?,
+            V1WaitCondition::Grpc { port, service } =>
+                probe::grpc(*port, service.as_deref(), connect_timeout).await?,
+            V1WaitCondition::UnixSocket(path) =>
+                probe::unix(path, connect_timeout).await?,
+            V1WaitCondition::Command { argv, .. } =>
+                probe::command(argv, command_timeout).await?,
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+//Checks liveness conditions periodically once the container is ready.
+//Returns when the entrypoint should be killed.
+pub async fn monitor(ctx: &Context, ready_signal: &ReadySignal,
+    sender: &Sender<V1Event>) {
+    let conditions = &ctx.setup.liveness;
+    if conditions.is_empty() {
+        return std::future::pending().await;
+    }
+    for condition in conditions {
+        if !matches!(condition, V1WaitCondition::Port(_)
+            | V1WaitCondition::Udp { .. }
+            | V1WaitCondition::Tls { .. }
+            | V1WaitCondition::Grpc { .. }
+            | V1WaitCondition::UnixSocket(_)
+            | V1WaitCondition::Command { .. }) {
+            log::warn!("Only port, UDP, TLS, gRPC, Unix socket and \
+                command conditions are supported for liveness, \
+                ignoring {condition:?}");
+        }
+    }
+
+    ready_signal.wait_ready().await;
+
+    let interval = Duration::from_millis(ctx.setup.liveness_interval_ms);
+    let mut failures = 0;
+    let mut healthy = true;
+    loop {
+        tokio::time::sleep(interval).await;
+        match check(ctx).await {
+            Ok(()) => {
+                failures = 0;
+                if !healthy {
+                    healthy = true;
+                    sender.send(V1Event::Healthy).await
+                        .expect("Cannot send event");
+                }
+            },
+            Err(reason) => {
+                failures += 1;
+                log::warn!("Liveness check failed ({failures} times): {reason}");
+                if healthy && failures >= ctx.setup.liveness_failure_threshold {
+                    healthy = false;
+                    sender.send(V1Event::Unhealthy(reason)).await
+                        .expect("Cannot send event");
+                }
+                if ctx.setup.liveness_kill_threshold
+                    .is_some_and(|threshold| failures >= threshold) {
+                    return;
+                }
+            },
+        }
+    }
+}
@@ -0,0 +1,191 @@
+/*
+ * Copyright 2024 Akash Rawal
+ *
+ * This file is part of Disposables.
+ *
+ * Disposables is free software: you can redistribute it and/or modify it under 
+ * the terms of the GNU General Public License as published by the 
+ * Free Software Foundation, either version 3 of the License, or 
+ * (at your option) any later version.
+ * 
+ * Disposables is distributed in the hope that it will be useful, 
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of 
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. 
+ * See the GNU General Public License for more details.
+ * 
+ * You should have received a copy of the GNU General Public License 
+ * along with Disposables. If not, see <https://www.gnu.org/licenses/>. 
+ */
+//Log lines written by DLC, for both the entrypoint's output and DLC's own
+//entries
+
+use std::fmt::Write as _;
+use std::io::Write as _;
+use std::sync::OnceLock;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use disposables_protocol::{V1LogConfig, V1LogFormat, V1OutputStream};
+
+struct Logger {
+    config: V1LogConfig,
+    start: Instant,
+}
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+fn logger() -> &'static Logger {
+    LOGGER.get_or_init(|| Logger {
+        config: V1LogConfig::default(),
+        start: Instant::now(),
+    })
+}
+
+//Formats the time as RFC 3339 in UTC, with millisecond precision.
+fn format_wall_clock(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, secs_of_day) = ((secs / 86400) as i64, secs % 86400);
+
+    //Days to civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        secs_of_day / 3600, secs_of_day % 3600 / 60, secs_of_day % 60,
+        since_epoch.subsec_millis())
+}
+
+impl Logger {
+    fn format(&self, tag: &str, level: Option<log::Level>, text: &str)
+    -> String {
+        let wall_clock = format_wall_clock(SystemTime::now());
+        let monotonic = self.start.elapsed().as_secs_f64();
+
+        match self.config.format {
+            V1LogFormat::Plain => {
+                let mut entry = String::new();
+                if self.config.timestamps {
+                    let _ = write!(entry, "{wall_clock} {monotonic:.3} ");
+                }
+                let _ = write!(entry, "[{tag}] ");
+                if let Some(level) = level {
+                    let _ = write!(entry, "{level}: ");
+                }
+                entry.push_str(text);
+                entry
+            },
+            V1LogFormat::Json => {
+                let mut entry = serde_json::json!({
+                    "time": wall_clock,
+                    "monotonic_s": monotonic,
+                    "stream": tag,
+                    "line": text,
+                });
+                if let Some(level) = level {
+                    entry["level"] = level.as_str().into();
+                }
+                entry.to_string()
+            },
+        }
+    }
+
+    fn write(&self, tag: &str, level: Option<log::Level>, text: &str) {
+        let entry = self.format(tag, level, text);
+        let _ = writeln!(std::io::stdout().lock(), "{entry}");
+    }
+}
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            self.write(&self.config.dlc_tag, Some(record.level()),
+                &record.args().to_string());
+        }
+    }
+
+    fn flush(&self) {
+        let _ = std::io::stdout().flush();
+    }
+}
+
+//Sets up the logger, and installs it for DLC's own entries.
+//Log level of DLC's entries can be set using RUST_LOG.
+pub fn init(config: V1LogConfig) {
+    let logger = LOGGER.get_or_init(|| Logger {
+        config,
+        start: Instant::now(),
+    });
+    let level = std::env::var("RUST_LOG").ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(log::LevelFilter::Info);
+    if log::set_logger(logger).is_ok() {
+        log::set_max_level(level);
+    }
+}
+
+pub fn stream_tag(kind: V1OutputStream) -> &'static str {
+    let config = &logger().config;
+    match kind {
+        V1OutputStream::Stdout => &config.stdout_tag,
+        V1OutputStream::Stderr => &config.stderr_tag,
+    }
+}
+
+//Writes a line of output from a process.
+pub fn output(tag: &str, line: &str) {
+    logger().write(tag, None, line);
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn wall_clock_is_formatted_as_rfc3339() {
+        let time = UNIX_EPOCH + Duration::from_millis(1_709_251_199_123);
+        assert_eq!(format_wall_clock(time), "2024-02-29T23:59:59.123Z");
+        assert_eq!(format_wall_clock(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+    }
+
+    #[test]
+    fn plain_entries_have_tag_prefix() {
+        let logger = Logger {
+            config: V1LogConfig::default(),
+            start: Instant::now(),
+        };
+        assert_eq!(logger.format("out", None, "hello"), "[out] hello");
+        assert_eq!(logger.format("dlc", Some(log::Level::Info), "ready"),
+            "[dlc] INFO: ready");
+    }
+
+    #[test]
+    fn json_entries_have_separate_fields() {
+        let logger = Logger {
+            config: V1LogConfig {
+                format: V1LogFormat::Json,
+                ..Default::default()
+            },
+            start: Instant::now(),
+        };
+        let entry: serde_json::Value = serde_json::from_str(
+            &logger.format("err", None, "oops")).unwrap();
+        assert_eq!(entry["stream"], "err");
+        assert_eq!(entry["line"], "oops");
+        assert!(entry["time"].is_string());
+        assert!(entry["monotonic_s"].is_number());
+        assert!(entry.get("level").is_none());
+    }
+}
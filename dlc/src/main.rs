@@ -16,165 +16,546 @@
  * You should have received a copy of the GNU General Public License 
  * along with Disposables. If not, see <https://www.gnu.org/licenses/>. 
  */
+mod grpc;
+mod listen;
+mod liveness;
+mod logger;
+mod output;
+mod pdu;
+mod probe;
+mod proxy;
+mod pty;
 mod ready;
+mod sidecar;
+mod tail;
+mod tunnel;
 
-use std::collections::HashSet;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashSet, VecDeque};
 use std::ffi::OsString;
+use std::os::fd::OwnedFd;
 use std::io::ErrorKind;
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::path::PathBuf;
-use std::process::Stdio;
+use std::process::{ExitStatus, Stdio};
 use std::time::Duration;
 
 use base64::Engine;
-use futures::FutureExt;
-use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use futures::{FutureExt, StreamExt};
+use futures::future::FusedFuture;
+use futures::stream::FuturesUnordered;
+use tokio::io::{AsyncBufRead, AsyncReadExt, AsyncWrite, BufReader};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::process::Command;
+use tokio::process::{Child, Command};
+use tokio::sync::{oneshot, watch};
 
-use disposables_protocol::{V1SetupMsg, V1WaitCondition, V1Event};
-use disposables_protocol::V1_ENV_SETUP;
+use disposables_protocol::{V1LogConfig, V1OutputStream, V1Proxy, V1SetupMsg};
+use disposables_protocol::{V1RestartPolicy, V1Sidecar, V1TermSize};
+use disposables_protocol::{V1Phase, V1WaitCondition};
+use disposables_protocol::V1Event;
+use disposables_protocol::{V1_ENV_SETUP, V1_INTERNAL_ERROR_PREFIX};
 use tokio::sync::mpsc::{Receiver, Sender};
 
+use output::LineReader;
+use pdu::write_pdu;
+use proxy::Proxies;
+use pty::PtyMaster;
 use ready::ReadySignal;
+use tail::Tail;
+use tunnel::PendingTunnels;
+
+const OUTPUT_DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+//Events kept while no client is connected
+const MAX_EVENT_BACKLOG: usize = 1024;
+//Limit of the delay before restarting the entrypoint
+const MAX_RESTART_BACKOFF: Duration = Duration::from_secs(30);
+//Time the entrypoint is given to exit after SIGTERM, before it is killed
+const STOP_GRACE_PERIOD: Duration = Duration::from_secs(10);
 
 struct MySetupMsg {
     files: Vec<(String, String)>,
     port: u16,
     wait_for: Vec<V1WaitCondition>,
+    phases: Vec<V1Phase>,
     ready_timeout_s: u64,
+    fail_on: Vec<String>,
+    forward_output: bool,
+    stdin: bool,
+    sidecars: Vec<V1Sidecar>,
+    pty: Option<V1TermSize>,
+    restart: V1RestartPolicy,
+    restart_backoff_ms: u64,
+    log: V1LogConfig,
+    max_line_length: usize,
+    reverse_tunnels: Vec<u16>,
+    proxies: Vec<V1Proxy>,
+    liveness: Vec<V1WaitCondition>,
+    liveness_interval_ms: u64,
+    liveness_failure_threshold: u32,
+    liveness_kill_threshold: Option<u32>,
     port_check_interval_ms: u64,
     client_timeout_s: u64,
+    probe_connect_timeout_ms: u64,
+    command_timeout_ms: Option<u64>,
+    token: Option<String>,
+    linger_s: Option<u64>,
+    max_lifetime_s: Option<u64>,
 }
 
-impl MySetupMsg {
-    fn fetch() -> Self {
-        let mut res = Self {
+impl Default for MySetupMsg {
+    fn default() -> Self {
+        Self {
             files: Vec::new(),
             port: 4,
             wait_for: Vec::new(),
+            phases: Vec::new(),
             ready_timeout_s: 120,
+            fail_on: Vec::new(),
+            forward_output: false,
+            stdin: false,
+            sidecars: Vec::new(),
+            pty: None,
+            restart: V1RestartPolicy::Never,
+            restart_backoff_ms: 1000,
+            log: V1LogConfig::default(),
+            max_line_length: output::DEFAULT_MAX_LINE_LENGTH,
+            reverse_tunnels: Vec::new(),
+            proxies: Vec::new(),
+            liveness: Vec::new(),
+            liveness_interval_ms: 1000,
+            liveness_failure_threshold: 3,
+            liveness_kill_threshold: None,
             port_check_interval_ms: 500,
             client_timeout_s: 15,
-        };
+            probe_connect_timeout_ms: 1000,
+            command_timeout_ms: None,
+            token: None,
+            linger_s: None,
+            max_lifetime_s: None,
+        }
+    }
+}
+
+impl MySetupMsg {
+    fn fetch() -> Result<Self, String> {
+        let mut res = Self::default();
 
         if let Ok(v) = std::env::var(V1_ENV_SETUP) {
             let msg = serde_json::from_str::<V1SetupMsg>(&v)
-                .unwrap_or_else(|e| {
-                    panic!("Unable to parse {} variable: {e}", V1_ENV_SETUP)
-                });
+                .map_err(|e| {
+                    format!("Unable to parse {} variable: {e}", V1_ENV_SETUP)
+                })?;
             res.files.extend(msg.files);
             res.port = msg.port;
             res.wait_for = msg.wait_for;
+            res.phases = msg.phases;
+            res.fail_on = msg.fail_on;
+            res.forward_output = msg.forward_output;
+            res.stdin = msg.stdin;
+            res.sidecars = msg.sidecars;
+            res.pty = msg.pty;
+            res.restart = msg.restart;
+            if let Some(v) = msg.restart_backoff_ms {
+                res.restart_backoff_ms = v;
+            }
+            res.log = msg.log;
+            res.reverse_tunnels = msg.reverse_tunnels;
+            res.proxies = msg.proxies;
+            res.liveness = msg.liveness;
             if let Some(v) = msg.ready_timeout_s {
                 res.ready_timeout_s = v;
             }
+            if let Some(v) = msg.client_timeout_s {
+                res.client_timeout_s = v;
+            }
+            if let Some(v) = msg.port_check_interval_ms {
+                res.port_check_interval_ms = v;
+            }
+            if let Some(v) = msg.probe_connect_timeout_ms {
+                res.probe_connect_timeout_ms = v;
+            }
+            res.command_timeout_ms = msg.command_timeout_ms;
+            res.token = msg.token;
+            res.linger_s = msg.linger_s;
+            res.max_lifetime_s = msg.max_lifetime_s;
+            if let Some(v) = msg.max_line_length {
+                res.max_line_length = v.max(1);
+            }
+            if let Some(v) = msg.liveness_interval_ms {
+                res.liveness_interval_ms = v;
+            }
+            if let Some(v) = msg.liveness_failure_threshold {
+                res.liveness_failure_threshold = v;
+            }
+            res.liveness_kill_threshold = msg.liveness_kill_threshold;
         }
 
+        res.validate()?;
+        Ok(res)
+    }
 
-        res
+    fn validate(&self) -> Result<(), String> {
+        for (name, value) in [
+            ("client_timeout_s", Some(self.client_timeout_s)),
+            ("port_check_interval_ms", Some(self.port_check_interval_ms)),
+            ("probe_connect_timeout_ms", Some(self.probe_connect_timeout_ms)),
+            ("command_timeout_ms", self.command_timeout_ms),
+            ("liveness_interval_ms", Some(self.liveness_interval_ms)),
+            ("liveness_failure_threshold",
+                Some(self.liveness_failure_threshold.into())),
+            ("restart_backoff_ms", Some(self.restart_backoff_ms)),
+            ("max_lifetime_s", self.max_lifetime_s),
+        ] {
+            if value == Some(0) {
+                return Err(format!("{name} must be greater than zero"));
+            }
+        }
+        if self.liveness_kill_threshold
+            .is_some_and(|kill| kill < self.liveness_failure_threshold) {
+            return Err("liveness_kill_threshold must not be smaller than \
+                liveness_failure_threshold".to_owned());
+        }
+        let mut names = HashSet::new();
+        if let Some(phase) = self.phases.iter()
+            .find(|phase| !names.insert(&phase.name)) {
+            return Err(format!("Phase {:?} is defined more than once",
+                phase.name));
+        }
+        //Their results are only reported for the entrypoint
+        if let Some(sidecar) = self.sidecars.iter()
+            .find(|sidecar| sidecar.wait_for.iter().any(|condition| matches!(
+                condition, V1WaitCondition::Listening(_)
+                    | V1WaitCondition::Tls{..}))) {
+            return Err(format!("Sidecar {:?} cannot wait for listening ports \
+                or TLS handshakes", sidecar.name));
+        }
+        Ok(())
+    }
+
+    fn probe_connect_timeout(&self) -> Duration {
+        Duration::from_millis(self.probe_connect_timeout_ms)
+    }
+
+    fn command_timeout(&self) -> Option<Duration> {
+        self.command_timeout_ms.map(Duration::from_millis)
     }
 }
 
+//Reports a failure that cannot be sent as an event, in a form the client
+//can find in the container's logs.
+fn report_internal_error(message: &str) {
+    log::error!("{message}");
+    let message = serde_json::to_string(message)
+        .expect("Cannot serialize error message");
+    eprintln!("{V1_INTERNAL_ERROR_PREFIX}{message}");
+}
+
 struct Context {
     setup: MySetupMsg,
     arg0: OsString,
     args: Vec<OsString>,
+    pending_tunnels: PendingTunnels,
+    proxies: Proxies,
+    //Connections that asked to become the control connection
+    attach_sender: Sender<TcpStream>,
+    //Time to keep running once the client disconnects, as asked by it
+    keep_s: Cell<Option<u64>>,
+    //Stdin of the entrypoint once started, till a client takes it
+    stdin: RefCell<Option<oneshot::Receiver<EntrypointStdin>>>,
+    //Master side of the pseudo-terminal the entrypoint runs on, if any
+    pty_master: RefCell<Option<OwnedFd>>,
+    //Set once the container has to stop
+    shutdown: watch::Sender<bool>,
 }
 
-async fn read_line(kind: &str, stream: &mut (impl AsyncBufRead + Unpin)) 
--> Option<String> {
-    let mut line = String::new();
-    let res = stream.read_line(&mut line).await
-        .expect("Cannot read from child process");
-    if res == 0 { 
-        None
-    } else {
-        let line = line.trim_end().to_owned();
-        println!("[{kind}] {line}");
-        Some(line)
+impl Context {
+    //Also returns the receiving end of connections that ask to become
+    //the control connection, and the sender for the stdin of the entrypoint.
+    fn new(setup: MySetupMsg, arg0: OsString, args: Vec<OsString>)
+    -> (Self, Receiver<TcpStream>, oneshot::Sender<EntrypointStdin>) {
+        let proxies = Proxies::new(&setup.proxies);
+        let (attach_sender, attach_receiver) = tokio::sync::mpsc::channel(1);
+        let (stdin_sender, stdin_receiver) = oneshot::channel();
+        let ctx = Context {
+            setup,
+            arg0,
+            args,
+            pending_tunnels: PendingTunnels::default(),
+            proxies,
+            attach_sender,
+            keep_s: Cell::new(None),
+            stdin: RefCell::new(Some(stdin_receiver)),
+            pty_master: RefCell::new(None),
+            shutdown: watch::Sender::new(false),
+        };
+        (ctx, attach_receiver, stdin_sender)
+    }
+}
+
+type EntrypointStdin = Box<dyn AsyncWrite + Unpin>;
+type EntrypointOutput = Box<dyn AsyncBufRead + Unpin>;
+
+//Checks a line of output against the patterns of the stdout wait conditions
+//that are not yet satisfied.
+async fn match_patterns(patterns: &mut Vec<&String>, line: &str,
+    source: &str, ready_signal: &ReadySignal) {
+    if patterns.is_empty() {
+        return;
+    }
+    let rm_list = patterns.iter()
+        .filter_map(|p| line.contains(*p).then_some(*p))
+        .collect::<HashSet<&String>>();
+
+    for pattern in &rm_list {
+        log::info!("Condition satisfied: found {pattern:?} in {source}");
+    }
+    let prev_len = patterns.len();
+    patterns.retain(|p| !rm_list.contains(p));
+    ready_signal.dec((prev_len - patterns.len()) as i32).await;
+}
+
+//Returns the patterns of the Stdout conditions
+fn stdout_patterns(conditions: &[V1WaitCondition]) -> Vec<&String> {
+    conditions.iter()
+        .filter_map(|c| match c {
+            V1WaitCondition::Stdout(pattern) => Some(pattern),
+            _ => None,
+        })
+        .collect()
+}
+
+//`patterns` holds the patterns to look for along with the signal of
+//the conditions they belong to.
+async fn scan_output(ctx: &Context, kind: V1OutputStream,
+    stream: &mut (impl AsyncBufRead + Unpin),
+    mut patterns: Vec<(Vec<&String>, &ReadySignal)>,
+    ready_signal: &ReadySignal, sender: &Sender<V1Event>) {
+    let tag = logger::stream_tag(kind);
+    let mut split_warned = false;
+    let mut reader = LineReader::new(stream, ctx.setup.max_line_length);
+    loop {
+        let line = match reader.read_line().await {
+            Ok(Some(line)) => line,
+            Ok(None) => break,
+            Err(e) => {
+                log::warn!("Cannot read {tag} of entrypoint: {e}");
+                sender.send(V1Event::OutputError {
+                    stream: kind,
+                    error: e.to_string(),
+                }).await.expect("Cannot send event");
+                break;
+            },
+        };
+        if line.continued && !split_warned {
+            log::warn!("Splitting {tag} lines longer than {} bytes",
+                ctx.setup.max_line_length);
+            split_warned = true;
+        }
+        let line = output::decode(&line.bytes);
+        logger::output(tag, &line);
+
+        if ctx.setup.forward_output {
+            sender.send(V1Event::Output { stream: kind, line: line.clone() })
+                .await.expect("Cannot send event");
+        }
+
+        for (patterns, signal) in &mut patterns {
+            match_patterns(patterns, &line, "stdout", signal).await;
+        }
+
+        if let Some(pattern) = ctx.setup.fail_on.iter()
+            .find(|p| line.contains(p.as_str())) {
+            ready_signal.fail(V1Event::FailedOutputMatch {
+                pattern: pattern.clone(),
+                line,
+            }).await;
+        }
     }
 }
 
-async fn scan_output(ctx: &Context, kind: &str, stream: &mut (impl AsyncBufRead + Unpin),
+//Checks the conditions other than Stdout, which are matched while
+//scanning the output.
+async fn check_conditions(ctx: &Context, conditions: &[V1WaitCondition],
+    pid: Option<u32>, ready_signal: &ReadySignal) {
+    futures::join!{
+        //Check ports for readiness
+        check_ports(ctx, conditions, ready_signal),
+        check_udp(ctx, conditions, ready_signal),
+        check_tls(ctx, conditions, ready_signal),
+        check_grpc(ctx, conditions, ready_signal),
+        check_unix_sockets(ctx, conditions, ready_signal),
+        //Check commands
+        check_commands(ctx, conditions, ready_signal),
+        //Check listening sockets
+        check_listening(ctx, conditions, pid, ready_signal),
+        //Check files
+        check_paths(ctx, conditions, ready_signal),
+        check_files(ctx, conditions, ready_signal),
+    };
+}
+
+//Checks the conditions of the phases, and reports the phases in order
+//as they are reached. Each phase has its own signal in `phase_signals`,
+//the entrypoint's signal counts the conditions of all the phases.
+async fn check_phases(ctx: &Context, phase_signals: &[ReadySignal],
+    pid: Option<u32>, ready_signal: &ReadySignal, sender: &Sender<V1Event>) {
+    let checks = ctx.setup.phases.iter().zip(phase_signals)
+        .map(|(phase, signal)| check_conditions(ctx, &phase.wait_for, pid,
+            signal));
+    let report = async {
+        for (phase, signal) in ctx.setup.phases.iter().zip(phase_signals) {
+            signal.wait_ready().await;
+            //Not reported after a timeout or a failure
+            if ready_signal.is_settled() {
+                return;
+            }
+            log::info!("Reached phase {}", phase.name);
+            sender.send(V1Event::PhaseReached(phase.name.clone())).await
+                .expect("Cannot send event");
+            signal.merge_into(ready_signal);
+            ready_signal.dec(phase.wait_for.len() as i32).await;
+        }
+    };
+    futures::join!(futures::future::join_all(checks), report);
+}
+
+async fn check_ports(ctx: &Context, conditions: &[V1WaitCondition],
     ready_signal: &ReadySignal) {
-    let mut patterns = Vec::new();
-    for condition in &ctx.setup.wait_for {
-        if let V1WaitCondition::Stdout(pattern) = condition {
-            patterns.push(pattern);
+    let interval = Duration::from_millis(ctx.setup.port_check_interval_ms);
+    let timeout = ctx.setup.probe_connect_timeout();
+    let mut futures = Vec::new();
+
+    for condition in conditions {
+        if let V1WaitCondition::Port(port) = condition {
+            futures.push(async move {
+                while probe::port(*port, timeout).await.is_err() {
+                    tokio::time::sleep(interval).await;
+                }
+                log::info!("Condition satisfied: port {port} is open");
+                ready_signal.dec(1).await;
+            });
         }
     }
 
-    while !patterns.is_empty() {
-        if let Some(line) = read_line(kind, stream).await {
-            let rm_list = patterns.iter()
-                .filter_map(|p| line.contains(*p).then_some(*p))
-                .collect::<HashSet<&String>>();
+    futures::future::join_all(futures).await;
+}
+
+async fn check_udp(ctx: &Context, conditions: &[V1WaitCondition],
+    ready_signal: &ReadySignal) {
+    let interval = Duration::from_millis(ctx.setup.port_check_interval_ms);
+    let timeout = ctx.setup.probe_connect_timeout();
+    let mut futures = Vec::new();
 
-            let prev_len = patterns.len();
-            patterns.retain(|p| !rm_list.contains(p));
-            ready_signal.dec((prev_len - patterns.len()) as i32).await;
-        } else {
-            break;
+    for condition in conditions {
+        if let V1WaitCondition::Udp { port, request, reply } = condition {
+            futures.push(async move {
+                while let Err(e) = probe::udp(*port, request, reply, timeout)
+                    .await {
+                    log::debug!("{e}");
+                    tokio::time::sleep(interval).await;
+                }
+                log::info!("Condition satisfied: UDP port {port} answered");
+                ready_signal.dec(1).await;
+            });
         }
     }
+
+    futures::future::join_all(futures).await;
 }
 
-async fn check_ports(ctx: &Context, ready_signal: &ReadySignal) {
+async fn check_tls(ctx: &Context, conditions: &[V1WaitCondition],
+    ready_signal: &ReadySignal) {
     let interval = Duration::from_millis(ctx.setup.port_check_interval_ms);
+    let timeout = ctx.setup.probe_connect_timeout();
     let mut futures = Vec::new();
 
-    for condition in &ctx.setup.wait_for {
-        if let V1WaitCondition::Port(port) = condition {
-            let port = *port;
-            let addrs = [(IpAddr::from(Ipv4Addr::LOCALHOST), port),
-                (IpAddr::from(Ipv6Addr::LOCALHOST), port)];
-            for addr in addrs {
-                let fut = async move {
-                    loop {
-                        let result = TcpStream::connect(addr).await;
-                        if result.is_ok() {
-                            break;
-                        }
-                        tokio::time::sleep(interval).await;
+    for condition in conditions {
+        if let V1WaitCondition::Tls { port, sni, ca_file } = condition {
+            futures.push(async move {
+                let certificate = loop {
+                    match probe::tls(*port, sni.as_deref(), ca_file.as_deref(),
+                        timeout).await {
+                        Ok(certificate) => break certificate,
+                        Err(e) => log::debug!("{e}"),
                     }
-                    ready_signal.dec(1).await;
+                    tokio::time::sleep(interval).await;
                 };
-                futures.push(fut);
-            }
+                log::info!("Condition satisfied: TLS handshake with port \
+                    {port} completed, certificate subject {}",
+                    certificate.subject);
+                ready_signal.add_certificate(certificate);
+                ready_signal.dec(1).await;
+            });
         }
     }
 
     futures::future::join_all(futures).await;
 }
 
-async fn check_commands(ctx: &Context, ready_signal: &ReadySignal) {
+async fn check_grpc(ctx: &Context, conditions: &[V1WaitCondition],
+    ready_signal: &ReadySignal) {
+    let interval = Duration::from_millis(ctx.setup.port_check_interval_ms);
+    let timeout = ctx.setup.probe_connect_timeout();
     let mut futures = Vec::new();
 
-    for condition in &ctx.setup.wait_for {
-        if let V1WaitCondition::Command { argv, interval_msec } = condition {
-            let mut iter = argv.iter();
-            let argv0 = match iter.next() {
-                Some(x) => x,
-                None => {
-                    log::warn!("Empty command given as wait condition");
-                    continue;
+    for condition in conditions {
+        if let V1WaitCondition::Grpc { port, service } = condition {
+            futures.push(async move {
+                while let Err(e) = probe::grpc(*port, service.as_deref(),
+                    timeout).await {
+                    log::debug!("{e}");
+                    tokio::time::sleep(interval).await;
                 }
-            };
-            let args = iter.collect::<Vec<_>>();
+                log::info!("Condition satisfied: port {port} is serving gRPC");
+                ready_signal.dec(1).await;
+            });
+        }
+    }
+
+    futures::future::join_all(futures).await;
+}
+
+async fn check_unix_sockets(ctx: &Context, conditions: &[V1WaitCondition],
+    ready_signal: &ReadySignal) {
+    let interval = Duration::from_millis(ctx.setup.port_check_interval_ms);
+    let timeout = ctx.setup.probe_connect_timeout();
+    let mut futures = Vec::new();
+
+    for condition in conditions {
+        if let V1WaitCondition::UnixSocket(path) = condition {
+            futures.push(async move {
+                while probe::unix(path, timeout).await.is_err() {
+                    tokio::time::sleep(interval).await;
+                }
+                log::info!("Condition satisfied: {path} is connectable");
+                ready_signal.dec(1).await;
+            });
+        }
+    }
+
+    futures::future::join_all(futures).await;
+}
+
+async fn check_commands(ctx: &Context, conditions: &[V1WaitCondition],
+    ready_signal: &ReadySignal) {
+    let timeout = ctx.setup.command_timeout();
+    let mut futures = Vec::new();
+
+    for condition in conditions {
+        if let V1WaitCondition::Command { argv, interval_msec } = condition {
+            if argv.is_empty() {
+                log::warn!("Empty command given as wait condition");
+                continue;
+            }
             futures.push(async move {
                 loop {
-                    let status = Command::new(argv0).args(&args).status().await;
-                    match status {
-                        Err(e) => {
-                            log::warn!("Unable to execute {argv:?}: {e}");
+                    match probe::command(argv, timeout).await {
+                        Ok(()) => {
+                            log::info!(
+                                "Condition satisfied: command {argv:?} succeeded");
+                            ready_signal.dec(1).await;
+                            break;
                         },
-                        Ok(status) => {
-                            if status.success() {
-                                ready_signal.dec(1).await; 
-                                break;
-                            }
-                        }
+                        Err(e) => log::debug!("{e}"),
                     }
                     if *interval_msec > 0 {
                         tokio::time::sleep(
@@ -190,117 +571,533 @@ async fn check_commands(ctx: &Context, ready_signal: &ReadySignal) {
     futures::future::join_all(futures).await;
 }
 
-async fn run_entrypoint(ctx: &Context, sender: Sender<V1Event>) {
+async fn check_listening(ctx: &Context, conditions: &[V1WaitCondition],
+    pid: Option<u32>, ready_signal: &ReadySignal) {
+    let interval = Duration::from_millis(ctx.setup.port_check_interval_ms);
+    let mut futures = Vec::new();
 
-    let start_res: Result<(), V1Event> = async {
-        //Write all files
-        for (path, base64) in &ctx.setup.files {
-            let bytes = base64::engine::general_purpose::STANDARD
-                .decode(base64)
-                .map_err(|e| format!("Failed to decode {path}: {e}"))
-                .map_err(V1Event::FailedToPrepare)?;
-            std::fs::write(path, bytes)
-                .map_err(|e| format!("Failed to write {path}: {e}"))
-                .map_err(V1Event::FailedToPrepare)?;
+    for condition in conditions {
+        if let V1WaitCondition::Listening(wanted) = condition {
+            //The process has already exited
+            let Some(pid) = pid else {
+                continue;
+            };
+            futures.push(async move {
+                loop {
+                    match listen::ports(pid) {
+                        Ok(ports) if !ports.is_empty()
+                            && wanted.iter().all(|p| ports.contains(p)) => {
+                            log::info!("Condition satisfied: \
+                                listening on {ports:?}");
+                            ready_signal.set_listening(ports);
+                            ready_signal.dec(1).await;
+                            break;
+                        },
+                        Ok(_) => (),
+                        Err(e) => log::debug!("Cannot list listening ports: {e}"),
+                    }
+                    tokio::time::sleep(interval).await;
+                }
+            });
         }
+    }
 
-        //Start the entrypoint
-        let mut child = Command::new(&ctx.arg0).args(&ctx.args)
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .spawn()
-            .map_err(|e| V1Event::FailedToStartEntrypoint(e.to_string()))?;
+    futures::future::join_all(futures).await;
+}
 
-        let stdout = child.stdout.take()
-            .expect("stdout of child process is None");
-        let mut stdout = BufReader::new(stdout);
+async fn check_paths(ctx: &Context, conditions: &[V1WaitCondition],
+    ready_signal: &ReadySignal) {
+    let interval = Duration::from_millis(ctx.setup.port_check_interval_ms);
+    let mut futures = Vec::new();
 
-        let stderr = child.stderr.take()
-            .expect("stderr of child process is None");
-        let mut stderr = BufReader::new(stderr);
+    for condition in conditions {
+        if let V1WaitCondition::PathExists(path) = condition {
+            futures.push(async move {
+                while !std::fs::exists(path).unwrap_or(false) {
+                    tokio::time::sleep(interval).await;
+                }
+                log::info!("Condition satisfied: {path} exists");
+                ready_signal.dec(1).await;
+            });
+        }
+    }
 
-        let ready_signal = ReadySignal::new(ctx.setup.wait_for.len() as i32, 
-            sender.clone());
+    futures::future::join_all(futures).await;
+}
 
-        futures::select!{
-            //Wait till child exits
-            _ = async {
-                let wait_res = child.wait().await
-                    .expect("Failed to wait for child");
-                sender.send(V1Event::Exited(wait_res.code())).await
-                    .expect("Cannot send event");
-            }.fuse() => (),
-            _ = async {
-                futures::join!{
-                    //Check stdout for readiness (and copy)
-                    async {
-                        scan_output(ctx, "out", &mut stdout, &ready_signal).await;
-                        while read_line("out", &mut stdout).await.is_some() { }
-                    },
-                    //Copy stderr
-                    async {
-                        while read_line("err", &mut stderr).await.is_some() { }
-                    },
-                    //Check ports for readiness
-                    check_ports(ctx, &ready_signal),
-                    //Check commands
-                    check_commands(ctx, &ready_signal),
-                    //Run the timeout
-                    async {
-                        let dur = Duration::from_secs(ctx.setup.ready_timeout_s);
-                        tokio::time::sleep(dur).await;
-
-                        ready_signal.timeout().await;
-                    },
-                };
-                futures::future::pending::<()>().await;
-            }.fuse() => (),
+async fn check_files(ctx: &Context, conditions: &[V1WaitCondition],
+    ready_signal: &ReadySignal) {
+    let interval = Duration::from_millis(ctx.setup.port_check_interval_ms);
+    let mut futures = Vec::new();
+
+    for condition in conditions {
+        if let V1WaitCondition::FileContains { path, pattern } = condition {
+            futures.push(async move {
+                let mut tail = Tail::new(path, ctx.setup.max_line_length);
+                loop {
+                    match tail.read_lines() {
+                        Ok(lines) if lines.iter()
+                            .any(|line| line.contains(pattern.as_str())) => {
+                            log::info!("Condition satisfied: \
+                                found {pattern:?} in {path}");
+                            ready_signal.dec(1).await;
+                            break;
+                        },
+                        Ok(_) => (),
+                        Err(e) => log::debug!("Cannot read {path}: {e}"),
+                    }
+                    tokio::time::sleep(interval).await;
+                }
+            });
+        }
+    }
+
+    futures::future::join_all(futures).await;
+}
+
+//Reason to stop the entrypoint before it exits on its own
+enum StopReason {
+    //Liveness checks failed
+    Unhealthy,
+    //DLC is shutting down
+    Shutdown,
+}
+
+//Resolves once DLC starts shutting down.
+async fn shutdown_requested(ctx: &Context) {
+    let _ = ctx.shutdown.subscribe().wait_for(|shutdown| *shutdown).await;
+}
+
+//Asks the child to stop, like `podman stop` does.
+fn terminate(child: &Child) -> std::io::Result<()> {
+    //The child has already been waited for
+    let Some(pid) = child.id() else {
+        return Ok(());
+    };
+    //SAFETY: kill() does not access memory of this process.
+    if unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) } == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+async fn kill_entrypoint(child: &mut Child, sender: &Sender<V1Event>) {
+    if let Err(e) = child.start_kill() {
+        let message = format!("Failed to kill entrypoint: {e}");
+        log::error!("{message}");
+        sender.send(V1Event::InternalError(message)).await
+            .expect("Cannot send event");
+    }
+}
+
+//Stops the container once its maximum lifetime is reached. Resolves when
+//DLC has to exit, even if the entrypoint has not stopped by then.
+async fn enforce_lifetime(ctx: &Context, sender: &Sender<V1Event>) {
+    let Some(max_lifetime_s) = ctx.setup.max_lifetime_s else {
+        return std::future::pending().await;
+    };
+    tokio::time::sleep(Duration::from_secs(max_lifetime_s)).await;
+    log::warn!("Maximum lifetime of {max_lifetime_s}s reached, stopping");
+    //The client may have stopped reading events
+    let _ = tokio::time::timeout(OUTPUT_DRAIN_TIMEOUT,
+        sender.send(V1Event::LifetimeExpired)).await;
+    ctx.shutdown.send_replace(true);
+
+    tokio::time::sleep(STOP_GRACE_PERIOD + 2 * OUTPUT_DRAIN_TIMEOUT).await;
+    log::warn!("Entrypoint did not stop in time, exiting");
+}
+
+//Runs the entrypoint once, till it exits.
+async fn run_once(ctx: &Context, sender: &Sender<V1Event>,
+    stdin_sender: Option<oneshot::Sender<EntrypointStdin>>)
+-> Result<ExitStatus, V1Event> {
+    //Start the entrypoint
+    let mut command = Command::new(&ctx.arg0);
+    command.args(&ctx.args);
+    let pty_master = match ctx.setup.pty {
+        Some(size) => Some(pty::attach(&mut command, size)
+            .map_err(|e| format!("Failed to open pseudo-terminal: {e}"))
+            .map_err(V1Event::FailedToPrepare)?),
+        None => {
+            let stdin = match (ctx.setup.stdin, &stdin_sender) {
+                (true, Some(_)) => Stdio::piped(),
+                //Only the first run reads the fed data
+                (true, None) => Stdio::null(),
+                (false, _) => Stdio::inherit(),
+            };
+            command.stdin(stdin)
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped());
+            None
+        }
+    };
+    //Both directions of the terminal go through the master side
+    let pty_io = pty_master.as_ref()
+        .map(|master| Ok::<_, std::io::Error>(
+            (PtyMaster::new(master)?, PtyMaster::new(master)?)))
+        .transpose()
+        .map_err(|e| format!("Failed to open pseudo-terminal: {e}"))
+        .map_err(V1Event::FailedToPrepare)?;
+
+    let mut child = command.spawn()
+        .map_err(|e| V1Event::FailedToStartEntrypoint(e.to_string()))?;
+    //Closes the copies of the terminal held for the child
+    drop(command);
+    let pid = child.id();
+    log::info!("Started entrypoint {:?} with PID {}", ctx.arg0,
+        pid.unwrap_or_default());
+    *ctx.pty_master.borrow_mut() = pty_master;
+
+    let (stdin, mut stdout, mut stderr): (Option<EntrypointStdin>,
+        EntrypointOutput, EntrypointOutput) = match pty_io {
+        Some((reader, writer)) => (
+            Some(Box::new(writer) as EntrypointStdin)
+                .filter(|_| ctx.setup.stdin),
+            Box::new(BufReader::new(reader)),
+            Box::new(tokio::io::empty()),
+        ),
+        None => (
+            child.stdin.take().map(|s| Box::new(s) as EntrypointStdin),
+            Box::new(BufReader::new(child.stdout.take()
+                .expect("stdout of child process is None"))),
+            Box::new(BufReader::new(child.stderr.take()
+                .expect("stderr of child process is None"))),
+        ),
+    };
+    if let (Some(stdin), Some(stdin_sender)) = (stdin, stdin_sender) {
+        let _ = stdin_sender.send(stdin);
+    }
+
+    let phase_signals: Vec<ReadySignal> = ctx.setup.phases.iter()
+        .map(|phase| ReadySignal::for_phase(phase.wait_for.len() as i32,
+            sender.clone(), phase.name.clone()))
+        .collect();
+    let condition_count = ctx.setup.wait_for.len()
+        + ctx.setup.phases.iter().map(|p| p.wait_for.len()).sum::<usize>();
+    let ready_signal = ReadySignal::new(condition_count as i32, 
+        sender.clone());
+
+    let stdout_patterns = std::iter::once(
+        (stdout_patterns(&ctx.setup.wait_for), &ready_signal))
+        .chain(ctx.setup.phases.iter().zip(&phase_signals)
+            .map(|(phase, signal)| (stdout_patterns(&phase.wait_for), signal)))
+        .collect();
+
+    let output = async {
+        futures::join!{
+            //Check stdout for readiness and failure (and copy)
+            scan_output(ctx, V1OutputStream::Stdout, &mut stdout,
+                stdout_patterns, &ready_signal, sender),
+            //Check stderr for failure (and copy)
+            scan_output(ctx, V1OutputStream::Stderr, &mut stderr,
+                Vec::new(), &ready_signal, sender),
         };
+    }.fuse();
+    futures::pin_mut!(output);
 
-        Ok(())
-    }.await;
+    let stop = futures::select!{
+        //Wait till child exits
+        _ = child.wait().fuse() => None,
+        //Check liveness once ready
+        _ = liveness::monitor(ctx, &ready_signal, sender).fuse()
+            => Some(StopReason::Unhealthy),
+        _ = shutdown_requested(ctx).fuse() => Some(StopReason::Shutdown),
+        _ = async {
+            output.as_mut().await;
+            futures::future::pending::<()>().await;
+        }.fuse() => None,
+        _ = async {
+            futures::join!{
+                check_conditions(ctx, &ctx.setup.wait_for, pid, &ready_signal),
+                check_phases(ctx, &phase_signals, pid, &ready_signal, sender),
+                //Run the timeout
+                async {
+                    let dur = Duration::from_secs(ctx.setup.ready_timeout_s);
+                    tokio::time::sleep(dur).await;
 
-    if let Err(event) = start_res {
-        sender.send(event).await.expect("Cannot send event");
+                    ready_signal.timeout().await;
+                },
+            };
+            futures::future::pending::<()>().await;
+        }.fuse() => None,
+    };
+
+    match stop {
+        Some(StopReason::Unhealthy) => {
+            log::warn!("Killing entrypoint after failed liveness checks");
+            kill_entrypoint(&mut child, sender).await;
+        },
+        Some(StopReason::Shutdown) => {
+            log::info!("Stopping entrypoint");
+            if let Err(e) = terminate(&child) {
+                log::warn!("Failed to terminate entrypoint: {e}");
+            }
+            let stopped = tokio::time::timeout(STOP_GRACE_PERIOD, child.wait())
+                .await;
+            if stopped.is_err() {
+                log::warn!("Entrypoint did not stop within \
+                    {STOP_GRACE_PERIOD:?}, killing it");
+                kill_entrypoint(&mut child, sender).await;
+            }
+        },
+        None => (),
+    }
+    let wait_res = child.wait().await
+        .map_err(|e| format!("Failed to wait for entrypoint: {e}"))
+        .map_err(V1Event::InternalError)?;
+    log::info!("Entrypoint exited: {wait_res}");
+    //Output may still be buffered in the pipes. Processes started by
+    //the entrypoint can keep the pipes open, so don't wait forever.
+    if !output.is_terminated() {
+        let _ = tokio::time::timeout(OUTPUT_DRAIN_TIMEOUT, output).await;
     }
+
+    Ok(wait_res)
 }
 
-async fn handle_client(ctx: &Context, mut receiver: Receiver<V1Event>) {
-    //Create TCP listener
-    let listen_addr = format!("[::]:{}", ctx.setup.port);
-    let listener = TcpListener::bind(&listen_addr).await
-        .unwrap_or_else(|e| panic!("Unable to listen on {}: {}",
-                listen_addr, e));
-
-    //Accept one connection with timeout.
-    let stream = futures::select! {
-        res = listener.accept().fuse() => {
-            res.expect("Unable to accept connection").0  
-        }, 
-        _ = tokio::time::sleep(Duration::from_secs(ctx.setup.client_timeout_s))
-            .fuse() => {
-            panic!("Timeout occured while waiting for connection, stopping");
+//Writes the files from the setup message.
+fn write_files(ctx: &Context) -> Result<(), String> {
+    for (path, base64) in &ctx.setup.files {
+        let bytes = base64::engine::general_purpose::STANDARD
+            .decode(base64)
+            .map_err(|e| format!("Failed to decode {path}: {e}"))?;
+        std::fs::write(path, bytes)
+            .map_err(|e| format!("Failed to write {path}: {e}"))?;
+    }
+    Ok(())
+}
+
+//Runs the entrypoint, restarting it according to the restart policy.
+async fn supervise_entrypoint(ctx: &Context, sender: &Sender<V1Event>,
+    stdin_sender: oneshot::Sender<EntrypointStdin>) -> Result<(), V1Event> {
+    let mut stdin_sender = Some(stdin_sender);
+    let mut backoff = Duration::from_millis(ctx.setup.restart_backoff_ms);
+    let mut count = 0;
+    loop {
+        let status = run_once(ctx, sender, stdin_sender.take()).await?;
+        let restart = !*ctx.shutdown.borrow() && match ctx.setup.restart {
+            V1RestartPolicy::Never => false,
+            V1RestartPolicy::OnFailure(max) => !status.success()
+                && count < max,
+            V1RestartPolicy::Always => true,
+        };
+        if !restart {
+            sender.send(V1Event::Exited(status.code())).await
+                .expect("Cannot send event");
+            return Ok(());
         }
+
+        count += 1;
+        log::warn!("Restarting entrypoint in {backoff:?} (restart #{count})");
+        sender.send(V1Event::Restarting { code: status.code(), count })
+            .await.expect("Cannot send event");
+        futures::select!{
+            _ = tokio::time::sleep(backoff).fuse() => (),
+            _ = shutdown_requested(ctx).fuse() => {
+                sender.send(V1Event::Exited(status.code())).await
+                    .expect("Cannot send event");
+                return Ok(());
+            },
+        };
+        backoff = (backoff * 2).min(MAX_RESTART_BACKOFF);
+    }
+}
+
+async fn run_entrypoint(ctx: &Context, sender: Sender<V1Event>,
+    stdin_sender: oneshot::Sender<EntrypointStdin>) {
+    if let Err(e) = write_files(ctx) {
+        sender.send(V1Event::FailedToPrepare(e)).await
+            .expect("Cannot send event");
+        return;
+    }
+
+    futures::join!{
+        sidecar::run_all(ctx, &sender),
+        async {
+            if let Err(event) = supervise_entrypoint(ctx, &sender,
+                stdin_sender).await {
+                sender.send(event).await.expect("Cannot send event");
+            }
+        },
     };
+}
 
+//Queues an event that could not be delivered to the client.
+fn push_backlog(backlog: &mut VecDeque<V1Event>, event: V1Event) {
+    if backlog.len() == MAX_EVENT_BACKLOG {
+        log::warn!("Too many undelivered events, dropping the oldest");
+        backlog.pop_front();
+    }
+    backlog.push_back(event);
+}
+
+//Sends events on the control connection till it is closed, or until
+//another connection takes its place, which is then returned.
+async fn serve_control(stream: TcpStream, receiver: &mut Receiver<V1Event>,
+    attach_receiver: &mut Receiver<TcpStream>,
+    backlog: &mut VecDeque<V1Event>) -> Option<TcpStream> {
     let (mut input, mut output) = tokio::io::split(stream);
 
     futures::select!{
         _ = async {
+            while let Some(event) = backlog.pop_front() {
+                if let Err(e) = write_pdu(&mut output, &event).await {
+                    log::error!("Cannot send event to client: {e}");
+                    backlog.push_front(event);
+                    return;
+                }
+            }
             while let Some(event) = receiver.recv().await {
-                let serialized = serde_json::to_vec(&event)
-                    .expect("Cannot serialize event");
-                async {
-                    output.write_u32(serialized.len() as u32).await?;
-                    output.write_all(&serialized).await
-                }.await.expect("Cannot send event to client");
+                if let Err(e) = write_pdu(&mut output, &event).await {
+                    log::error!("Cannot send event to client: {e}");
+                    push_backlog(backlog, event);
+                    return;
+                }
             }
             std::future::pending::<()>().await;
-        }.fuse() => (),
+        }.fuse() => None,
         _ = async {
-            //TODO: Temp code to respond to closing connection
+            //The client sends nothing on this connection, reading only
+            //detects that it was closed.
             let _ = input.read_u8().await;
+        }.fuse() => None,
+        stream = attach_receiver.recv().fuse() => {
+            log::info!("Client reattached, replacing control connection");
+            stream
+        },
+    }
+}
+
+//Keeps events while no client is connected, until a client reattaches
+//or the linger period is over.
+async fn linger(duration: Duration, receiver: &mut Receiver<V1Event>,
+    attach_receiver: &mut Receiver<TcpStream>,
+    backlog: &mut VecDeque<V1Event>) -> Option<TcpStream> {
+    futures::select!{
+        _ = async {
+            while let Some(event) = receiver.recv().await {
+                push_backlog(backlog, event);
+            }
+            std::future::pending::<()>().await;
+        }.fuse() => None,
+        stream = attach_receiver.recv().fuse() => {
+            log::info!("Client reattached");
+            stream
+        },
+        _ = tokio::time::sleep(duration).fuse() => None,
+    }
+}
+
+//Listens on the DLC port. Failures can only be reported in the logs,
+//as there is no client connection yet.
+async fn listen(ctx: &Context) -> Option<TcpListener> {
+    let listen_addr = format!("[::]:{}", ctx.setup.port);
+    match TcpListener::bind(&listen_addr).await {
+        Ok(listener) => Some(listener),
+        Err(e) => {
+            report_internal_error(
+                &format!("Unable to listen on {listen_addr}: {e}"));
+            //Keep the container around so that the client can read
+            //the error from its logs.
+            tokio::time::sleep(Duration::from_secs(ctx.setup.client_timeout_s))
+                .await;
+            None
+        }
+    }
+}
+
+//Without the setup message there is nothing to run, but the client can
+//still connect on the default port to learn why. Connections are answered
+//with the error until the client timeout passes.
+async fn serve_setup_error(ctx: &Context, listener: &TcpListener,
+    error: &str) {
+    let client_timeout = Duration::from_secs(ctx.setup.client_timeout_s);
+    let mut control = true;
+    let mut connections = FuturesUnordered::new();
+    futures::select! {
+        _ = async {
+            loop {
+                futures::select! {
+                    res = listener.accept().fuse() => match res {
+                        Ok((stream, _)) => connections.push(tunnel::refuse(
+                            ctx, stream, error, std::mem::take(&mut control))),
+                        Err(e) => log::warn!("Unable to accept connection: {e}"),
+                    },
+                    _ = connections.select_next_some() => (),
+                }
+            }
+        }.fuse() => (),
+        _ = tokio::time::sleep(client_timeout).fuse() => (),
+    }
+}
+
+async fn handle_client(ctx: &Context, listener: &TcpListener,
+    mut receiver: Receiver<V1Event>, mut attach_receiver: Receiver<TcpStream>) {
+    let client_timeout = Duration::from_secs(ctx.setup.client_timeout_s);
+
+    //Clients that do not authenticate use the first connection as
+    //the control connection. Others ask for it with V1Request::Attach,
+    //so that their requests can be served from the start.
+    let first = match ctx.setup.token {
+        None => futures::select! {
+            res = listener.accept().fuse() => match res {
+                Ok((stream, addr)) => {
+                    log::info!("Client connected from {addr}");
+                    Some(stream)
+                },
+                Err(e) => {
+                    report_internal_error(
+                        &format!("Unable to accept connection: {e}"));
+                    return;
+                },
+            },
+            _ = tokio::time::sleep(client_timeout).fuse() => {
+                report_internal_error(
+                    "Timeout occured while waiting for connection, stopping");
+                return;
+            }
+        },
+        Some(_) => None,
+    };
+
+    futures::select!{
+        _ = async {
+            let mut backlog = VecDeque::new();
+            let mut stream = match first {
+                Some(stream) => Some(stream),
+                None => futures::select! {
+                    stream = attach_receiver.recv().fuse() => {
+                        log::info!("Client attached");
+                        stream
+                    },
+                    _ = tokio::time::sleep(client_timeout).fuse() => {
+                        report_internal_error("Timeout occured while \
+                            waiting for client to attach, stopping");
+                        return;
+                    }
+                },
+            };
+            loop {
+                while let Some(s) = stream {
+                    stream = serve_control(s, &mut receiver,
+                        &mut attach_receiver, &mut backlog).await;
+                }
+                let linger_s = ctx.keep_s.get().max(ctx.setup.linger_s);
+                let Some(linger_s) = linger_s else {
+                    return;
+                };
+                log::warn!("Client disconnected, waiting {linger_s}s \
+                    for it to reattach");
+                stream = linger(Duration::from_secs(linger_s), &mut receiver,
+                    &mut attach_receiver, &mut backlog).await;
+                if stream.is_none() {
+                    log::warn!("Client did not reattach, stopping");
+                    return;
+                }
+            }
         }.fuse() => (),
+        //Other connections are requests and tunnels
+        _ = tunnel::serve(ctx, listener).fuse() => (),
     };
 }
 
@@ -348,20 +1145,48 @@ async fn async_main() {
         let arg0 = args.next().expect("Entrypoint is missing");
         let args = args.collect::<Vec<_>>();
 
-        let ctx = Context {
-            setup: MySetupMsg::fetch(),
-            arg0,
-            args
+        let (setup, setup_error) = match MySetupMsg::fetch() {
+            Ok(setup) => (setup, None),
+            Err(e) => (MySetupMsg::default(), Some(e)),
         };
+        logger::init(setup.log.clone());
+        let (ctx, attach_receiver, stdin_sender) =
+            Context::new(setup, arg0, args);
+
+        if let Some(e) = &setup_error {
+            report_internal_error(e);
+        }
+        //Listen before starting anything else, so that no other listener
+        //can take the DLC port
+        let Some(listener) = listen(&ctx).await else {
+            return;
+        };
+        if let Some(e) = setup_error {
+            serve_setup_error(&ctx, &listener, &e).await;
+            return;
+        }
 
         let (sender, receiver) = tokio::sync::mpsc::channel::<V1Event>(1);
 
         futures::select!{
             _ = async {
-                run_entrypoint(&ctx, sender).await;
+                run_entrypoint(&ctx, sender.clone(), stdin_sender).await;
+                //Once the container has to stop, exit after the entrypoint
+                shutdown_requested(&ctx).await;
+                //Give the client a moment to receive the last events
+                tokio::time::sleep(OUTPUT_DRAIN_TIMEOUT).await;
+            }.fuse() => (),
+            _ = enforce_lifetime(&ctx, &sender).fuse() => (),
+            _ = async {
+                tunnel::serve_reverse(&ctx, sender.clone()).await;
+                std::future::pending::<()>().await;
+            }.fuse() => (),
+            _ = async {
+                proxy::serve(&ctx, sender.clone()).await;
                 std::future::pending::<()>().await;
             }.fuse() => (),
-            _ = handle_client(&ctx, receiver).fuse() => ()
+            _ = handle_client(&ctx, &listener, receiver,
+                attach_receiver).fuse() => ()
         };
     } else {
         panic!("Invalid command {}", cmd.to_string_lossy());
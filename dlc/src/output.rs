@@ -0,0 +1,173 @@
+/*
+ * Copyright 2024 Akash Rawal
+ *
+ * This file is part of Disposables.
+ *
+ * Disposables is free software: you can redistribute it and/or modify it under 
+ * the terms of the GNU General Public License as published by the 
+ * Free Software Foundation, either version 3 of the License, or 
+ * (at your option) any later version.
+ * 
+ * Disposables is distributed in the hope that it will be useful, 
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of 
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. 
+ * See the GNU General Public License for more details.
+ * 
+ * You should have received a copy of the GNU General Public License 
+ * along with Disposables. If not, see <https://www.gnu.org/licenses/>. 
+ */
+//Reading lines of output from the entrypoint
+
+use tokio::io::{AsyncBufRead, AsyncBufReadExt};
+
+pub const DEFAULT_MAX_LINE_LENGTH: usize = 65536;
+
+//A line of output without the line terminator.
+pub struct Line {
+    pub bytes: Vec<u8>,
+    //Set when the bytes continue a line that was split
+    pub continued: bool,
+}
+
+//Reads lines of output. Lines longer than max_len bytes are split, so that
+//memory use stays bounded.
+pub struct LineReader<R> {
+    stream: R,
+    max_len: usize,
+    line: Vec<u8>,
+    continued: bool,
+    //Set after a full line was returned, as its newline may be yet to come
+    after_full: bool,
+}
+
+impl<R: AsyncBufRead + Unpin> LineReader<R> {
+    pub fn new(stream: R, max_len: usize) -> Self {
+        LineReader {
+            stream,
+            max_len,
+            line: Vec::new(),
+            continued: false,
+            after_full: false,
+        }
+    }
+
+    //Returns None at the end of the stream.
+    pub async fn read_line(&mut self) -> std::io::Result<Option<Line>> {
+        loop {
+            let buf = self.stream.fill_buf().await?;
+            if buf.is_empty() {
+                return Ok((!self.line.is_empty()).then(|| self.take_line()));
+            }
+
+            //A newline right after a full line still belongs to it
+            if std::mem::take(&mut self.after_full) {
+                if buf[0] == b'\n' {
+                    self.stream.consume(1);
+                    continue;
+                }
+                self.continued = true;
+            }
+
+            let room = self.max_len - self.line.len();
+            let window = &buf[..buf.len().min(room)];
+            if let Some(pos) = window.iter().position(|b| *b == b'\n') {
+                self.line.extend_from_slice(&window[..pos]);
+                self.stream.consume(pos + 1);
+                return Ok(Some(self.take_line()));
+            }
+
+            let len = window.len();
+            self.line.extend_from_slice(window);
+            self.stream.consume(len);
+            if self.line.len() >= self.max_len {
+                self.after_full = true;
+                return Ok(Some(self.take_line()));
+            }
+        }
+    }
+
+    fn take_line(&mut self) -> Line {
+        Line {
+            bytes: std::mem::take(&mut self.line),
+            continued: std::mem::take(&mut self.continued),
+        }
+    }
+}
+
+//Decodes a line of output for matching and logging. Invalid UTF-8 is
+//replaced rather than rejected.
+pub fn decode(line: &[u8]) -> String {
+    String::from_utf8_lossy(line).trim_end().to_owned()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+    use tokio::io::AsyncWriteExt;
+
+    async fn read_all(input: &[u8], max_len: usize) -> Vec<Vec<u8>> {
+        let mut reader = LineReader::new(input, max_len);
+        let mut lines = Vec::new();
+        while let Some(line) = reader.read_line().await.unwrap() {
+            lines.push(line.bytes);
+        }
+        lines
+    }
+
+    #[tokio::test]
+    async fn lines_are_split_on_newline() {
+        assert_eq!(read_all(b"one\ntwo\n\nthree", 100).await,
+            vec![b"one".to_vec(), b"two".to_vec(), b"".to_vec(),
+                b"three".to_vec()]);
+    }
+
+    #[tokio::test]
+    async fn long_lines_are_split_at_max_length() {
+        assert_eq!(read_all(b"abcdefg\nhi\n", 3).await,
+            vec![b"abc".to_vec(), b"def".to_vec(), b"g".to_vec(),
+                b"hi".to_vec()]);
+    }
+
+    #[tokio::test]
+    async fn line_of_exactly_max_length_is_not_split() {
+        assert_eq!(read_all(b"abc\nd\n", 3).await,
+            vec![b"abc".to_vec(), b"d".to_vec()]);
+    }
+
+    #[tokio::test]
+    async fn newline_after_full_line_in_next_read_is_not_a_line() {
+        let input = tokio::io::BufReader::with_capacity(3, &b"abc\nd\n"[..]);
+        let mut reader = LineReader::new(input, 3);
+        let line = reader.read_line().await.unwrap().unwrap();
+        assert_eq!((line.bytes, line.continued), (b"abc".to_vec(), false));
+        let line = reader.read_line().await.unwrap().unwrap();
+        assert_eq!((line.bytes, line.continued), (b"d".to_vec(), false));
+        assert!(reader.read_line().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn split_lines_are_marked_as_continued() {
+        let mut reader = LineReader::new(&b"abcdefg\nhi\n"[..], 3);
+        let mut continued = Vec::new();
+        while let Some(line) = reader.read_line().await.unwrap() {
+            continued.push(line.continued);
+        }
+        assert_eq!(continued, [false, true, true, false]);
+    }
+
+    #[tokio::test]
+    async fn full_line_is_returned_without_waiting_for_more_output() {
+        let (mut writer, input) = tokio::io::duplex(16);
+        writer.write_all(b"abc").await.unwrap();
+        let mut reader = LineReader::new(tokio::io::BufReader::new(input), 3);
+        let line = tokio::time::timeout(Duration::from_secs(1),
+            reader.read_line()).await.unwrap().unwrap();
+        assert_eq!(line.map(|line| line.bytes), Some(b"abc".to_vec()));
+    }
+
+    #[test]
+    fn invalid_utf8_is_decoded_lossily() {
+        assert_eq!(decode(b"caf\xc3\xa9 \xff\xfe!\r"), "caf\u{e9} \u{fffd}\u{fffd}!");
+    }
+}
@@ -0,0 +1,53 @@
+/*
+ * Copyright 2024 Akash Rawal
+ *
+ * This file is part of Disposables.
+ *
+ * Disposables is free software: you can redistribute it and/or modify it under 
+ * the terms of the GNU General Public License as published by the 
+ * Free Software Foundation, either version 3 of the License, or 
+ * (at your option) any later version.
+ * 
+ * Disposables is distributed in the hope that it will be useful, 
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of 
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. 
+ * See the GNU General Public License for more details.
+ * 
+ * You should have received a copy of the GNU General Public License 
+ * along with Disposables. If not, see <https://www.gnu.org/licenses/>. 
+ */
+//Length-prefixed JSON PDUs exchanged with the client
+
+use std::io::{Error, ErrorKind};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+//Requests are small, anything larger than this is not from our client.
+const MAX_PDU_SIZE: u32 = 16 * 1024 * 1024;
+
+pub async fn read_pdu<T>(stream: &mut (impl AsyncRead + Unpin))
+-> Result<T, Error>
+where for<'a> T: serde::Deserialize<'a>
+{
+    let size = stream.read_u32().await?;
+    if size > MAX_PDU_SIZE {
+        return Err(Error::new(ErrorKind::InvalidData,
+                format!("PDU too large ({size} bytes)")));
+    }
+
+    let mut pdu_body = vec![0_u8; size as usize];
+    stream.read_exact(&mut pdu_body).await?;
+
+    serde_json::from_slice(&pdu_body)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, e))
+}
+
+pub async fn write_pdu<T>(stream: &mut (impl AsyncWrite + Unpin), value: &T)
+-> Result<(), Error>
+where T: serde::Serialize
+{
+    let serialized = serde_json::to_vec(value)
+        .expect("Cannot serialize PDU");
+    stream.write_u32(serialized.len() as u32).await?;
+    stream.write_all(&serialized).await
+}
@@ -0,0 +1,265 @@
+/*
+ * Copyright 2024 Akash Rawal
+ *
+ * This file is part of Disposables.
+ *
+ * Disposables is free software: you can redistribute it and/or modify it under 
+ * the terms of the GNU General Public License as published by the 
+ * Free Software Foundation, either version 3 of the License, or 
+ * (at your option) any later version.
+ * 
+ * Disposables is distributed in the hope that it will be useful, 
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of 
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. 
+ * See the GNU General Public License for more details.
+ * 
+ * You should have received a copy of the GNU General Public License 
+ * along with Disposables. If not, see <https://www.gnu.org/licenses/>. 
+ */
+//Single checks shared by readiness and liveness conditions
+
+use std::time::Duration;
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::Arc;
+
+use tokio::net::{UdpSocket, UnixStream};
+use tokio::process::Command;
+use tokio_rustls::TlsConnector;
+use tokio_rustls::rustls::{ClientConfig, DigitallySignedStruct, RootCertStore,
+    SignatureScheme};
+use tokio_rustls::rustls::client::danger::{HandshakeSignatureValid,
+    ServerCertVerified, ServerCertVerifier};
+use tokio_rustls::rustls::crypto::{CryptoProvider, ring,
+    verify_tls12_signature, verify_tls13_signature};
+use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+
+use disposables_protocol::V1Certificate;
+use sha2::{Digest, Sha256};
+
+use crate::grpc;
+use crate::tunnel::connect_local;
+
+pub async fn port(port: u16, timeout: Duration) -> Result<(), String> {
+    match tokio::time::timeout(timeout, connect_local(port)).await {
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err(e)) => Err(format!("Unable to connect to port {port}: {e}")),
+        Err(_) => Err(format!("Timed out connecting to port {port}")),
+    }
+}
+
+//Largest possible UDP payload
+const MAX_DATAGRAM: usize = 65536;
+
+async fn udp_exchange(addr: IpAddr, port: u16, request: &[u8])
+-> Result<Vec<u8>, std::io::Error> {
+    let unspecified = match addr {
+        IpAddr::V4(_) => IpAddr::from(Ipv4Addr::UNSPECIFIED),
+        IpAddr::V6(_) => IpAddr::from(Ipv6Addr::UNSPECIFIED),
+    };
+    let socket = UdpSocket::bind((unspecified, 0)).await?;
+    //Connecting makes ICMP port unreachable errors visible to recv()
+    socket.connect((addr, port)).await?;
+    socket.send(request).await?;
+    let mut buf = vec![0; MAX_DATAGRAM];
+    let len = socket.recv(&mut buf).await?;
+    buf.truncate(len);
+    Ok(buf)
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    needle.is_empty()
+        || haystack.windows(needle.len()).any(|w| w == needle)
+}
+
+pub async fn udp(port: u16, request: &[u8], reply: &[u8], timeout: Duration)
+-> Result<(), String> {
+    let exchange = async {
+        match udp_exchange(IpAddr::from(Ipv4Addr::LOCALHOST), port, request)
+            .await {
+            Ok(data) => Ok(data),
+            Err(_) => udp_exchange(IpAddr::from(Ipv6Addr::LOCALHOST), port,
+                request).await,
+        }
+    };
+    match tokio::time::timeout(timeout, exchange).await {
+        Ok(Ok(data)) if contains(&data, reply) => Ok(()),
+        Ok(Ok(data)) => Err(format!("Unexpected reply from UDP port {port}: \
+            {:?}", String::from_utf8_lossy(&data))),
+        Ok(Err(e)) => Err(format!("Unable to reach UDP port {port}: {e}")),
+        Err(_) => Err(format!("Timed out waiting for UDP port {port}")),
+    }
+}
+
+//Accepts any certificate, but still checks that the server holds its key
+#[derive(Debug)]
+struct NoVerification(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for NoVerification {
+    fn verify_server_cert(&self, _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>], _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8], _now: UnixTime)
+    -> Result<ServerCertVerified, tokio_rustls::rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(&self, message: &[u8],
+        cert: &CertificateDer<'_>, dss: &DigitallySignedStruct)
+    -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        verify_tls12_signature(message, cert, dss,
+            &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(&self, message: &[u8],
+        cert: &CertificateDer<'_>, dss: &DigitallySignedStruct)
+    -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        verify_tls13_signature(message, cert, dss,
+            &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+fn tls_config(ca_file: Option<&str>) -> Result<ClientConfig, String> {
+    let provider = Arc::new(ring::default_provider());
+    let builder = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| format!("Cannot configure TLS: {e}"))?;
+    let config = match ca_file {
+        Some(ca_file) => {
+            let mut roots = RootCertStore::empty();
+            for cert in CertificateDer::pem_file_iter(ca_file)
+                .map_err(|e| format!("Cannot read {ca_file}: {e}"))? {
+                let cert = cert
+                    .map_err(|e| format!("Cannot parse {ca_file}: {e}"))?;
+                roots.add(cert)
+                    .map_err(|e| format!("Invalid certificate in {ca_file}: \
+                        {e}"))?;
+            }
+            builder.with_root_certificates(roots).with_no_client_auth()
+        },
+        None => builder.dangerous()
+            .with_custom_certificate_verifier(
+                Arc::new(NoVerification(provider)))
+            .with_no_client_auth(),
+    };
+    Ok(config)
+}
+
+fn describe_certificate(port: u16, der: &[u8])
+-> Result<V1Certificate, String> {
+    let (_, cert) = x509_parser::parse_x509_certificate(der)
+        .map_err(|e| format!("Cannot parse certificate from port {port}: \
+            {e}"))?;
+    let sha256 = Sha256::digest(der).iter()
+        .map(|b| format!("{b:02x}"))
+        .collect();
+    Ok(V1Certificate {
+        port,
+        subject: cert.subject().to_string(),
+        not_after: cert.validity().not_after.timestamp(),
+        sha256,
+    })
+}
+
+//Completes a TLS handshake with the port, and returns the certificate
+//presented by it.
+pub async fn tls(port: u16, sni: Option<&str>, ca_file: Option<&str>,
+    timeout: Duration) -> Result<V1Certificate, String> {
+    let connector = TlsConnector::from(Arc::new(tls_config(ca_file)?));
+    let server_name = match sni {
+        Some(sni) => ServerName::try_from(sni.to_owned())
+            .map_err(|e| format!("Invalid server name {sni:?}: {e}"))?,
+        //No SNI is sent for IP addresses
+        None => ServerName::from(IpAddr::from(Ipv4Addr::LOCALHOST)),
+    };
+    let handshake = async {
+        let stream = connect_local(port).await
+            .map_err(|e| format!("Unable to connect to port {port}: {e}"))?;
+        connector.connect(server_name, stream).await
+            .map_err(|e| format!("TLS handshake with port {port} failed: {e}"))
+    };
+    let stream = tokio::time::timeout(timeout, handshake).await
+        .map_err(|_| format!("Timed out during TLS handshake with \
+            port {port}"))??;
+    let (_, conn) = stream.get_ref();
+    let der = conn.peer_certificates()
+        .and_then(|certs| certs.first())
+        .ok_or_else(|| format!("Port {port} presented no certificate"))?;
+    describe_certificate(port, der)
+}
+
+pub async fn grpc(port: u16, service: Option<&str>, timeout: Duration)
+-> Result<(), String> {
+    let check = async {
+        let stream = connect_local(port).await
+            .map_err(|e| format!("Unable to connect to port {port}: {e}"))?;
+        grpc::health_check(stream, &format!("localhost:{port}"),
+            service.unwrap_or_default()).await
+            .map_err(|e| format!("gRPC health check of port {port} failed: \
+                {e}"))
+    };
+    tokio::time::timeout(timeout, check).await
+        .map_err(|_| format!("Timed out checking gRPC health of port {port}"))?
+}
+
+pub async fn unix(path: &str, timeout: Duration) -> Result<(), String> {
+    match tokio::time::timeout(timeout, UnixStream::connect(path)).await {
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err(e)) => Err(format!("Unable to connect to {path}: {e}")),
+        Err(_) => Err(format!("Timed out connecting to {path}")),
+    }
+}
+
+pub async fn command(argv: &[String], timeout: Option<Duration>)
+-> Result<(), String> {
+    let (argv0, args) = argv.split_first()
+        .ok_or_else(|| "Empty command".to_owned())?;
+    //Dropping the future on timeout kills the command
+    let status = Command::new(argv0).args(args).kill_on_drop(true).status();
+    let res = match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, status).await
+            .map_err(|_| format!("Command {argv:?} timed out"))?,
+        None => status.await,
+    };
+    match res {
+        Err(e) => Err(format!("Unable to execute {argv:?}: {e}")),
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(format!("Command {argv:?} failed with {status}")),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reply_pattern_is_found_in_datagram() {
+        assert!(contains(b"", b""));
+        assert!(contains(b"pong", b""));
+        assert!(contains(b"xxpongxx", b"pong"));
+        assert!(!contains(b"pon", b"pong"));
+    }
+
+    #[tokio::test]
+    async fn udp_reply_is_matched() {
+        let server = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let port = server.local_addr().unwrap().port();
+        let timeout = Duration::from_secs(1);
+        let serve = async {
+            let mut buf = [0; 16];
+            for _ in 0..2 {
+                let (_, peer) = server.recv_from(&mut buf).await.unwrap();
+                server.send_to(b"pong", peer).await.unwrap();
+            }
+        };
+        let probe = async {
+            assert!(udp(port, b"ping", b"pong", timeout).await.is_ok());
+            assert!(udp(port, b"ping", b"pang", timeout).await.is_err());
+        };
+        futures::join!(serve, probe);
+    }
+}
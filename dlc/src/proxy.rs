@@ -0,0 +1,252 @@
+/*
+ * Copyright 2024 Akash Rawal
+ *
+ * This file is part of Disposables.
+ *
+ * Disposables is free software: you can redistribute it and/or modify it under 
+ * the terms of the GNU General Public License as published by the 
+ * Free Software Foundation, either version 3 of the License, or 
+ * (at your option) any later version.
+ * 
+ * Disposables is distributed in the hope that it will be useful, 
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of 
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. 
+ * See the GNU General Public License for more details.
+ * 
+ * You should have received a copy of the GNU General Public License 
+ * along with Disposables. If not, see <https://www.gnu.org/licenses/>. 
+ */
+//Proxied ports with fault injection
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use futures::{FutureExt, StreamExt};
+use futures::stream::FuturesUnordered;
+use socket2::SockRef;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::Sender;
+use tokio::sync::watch;
+
+use disposables_protocol::{V1Event, V1Proxy, V1Toxic, V1Toxics};
+
+use crate::Context;
+use crate::tunnel::connect_local;
+
+//Toxics of all proxies, indexed by target port
+pub struct Proxies {
+    toxics: HashMap<u16, watch::Sender<V1Toxics>>,
+}
+
+impl Proxies {
+    pub fn new(proxies: &[V1Proxy]) -> Self {
+        Self {
+            toxics: proxies.iter()
+                .map(|p| (p.target, watch::Sender::new(V1Toxics::default())))
+                .collect()
+        }
+    }
+
+    pub fn set_toxics(&self, port: u16, toxics: V1Toxics)
+    -> Result<(), String> {
+        let sender = self.toxics.get(&port)
+            .ok_or_else(|| format!("Port {port} is not proxied"))?;
+        sender.send_replace(toxics);
+        Ok(())
+    }
+}
+
+fn is_reset(toxics: &[V1Toxic]) -> bool {
+    toxics.iter().any(|t| matches!(t, V1Toxic::ResetPeer))
+}
+
+struct Reset;
+
+//Copies data in one direction, applying the toxics as they are at the time
+//the data is read.
+async fn pump(reader: &mut (impl AsyncRead + Unpin),
+    writer: &mut (impl AsyncWrite + Unpin),
+    mut toxics: watch::Receiver<V1Toxics>,
+    select: impl Fn(&V1Toxics) -> &Vec<V1Toxic>)
+-> Result<(), Reset> {
+    let mut buf = vec![0_u8; 16 * 1024];
+    loop {
+        if is_reset(select(&toxics.borrow_and_update())) {
+            return Err(Reset);
+        }
+
+        let len = futures::select! {
+            res = reader.read(&mut buf).fuse() => match res {
+                Ok(len) => len,
+                Err(_) => return Err(Reset),
+            },
+            res = toxics.changed().fuse() => {
+                //Sender lives as long as DLC
+                res.expect("Toxics sender dropped");
+                continue;
+            },
+        };
+        if len == 0 {
+            let _ = writer.shutdown().await;
+            return Ok(());
+        }
+
+        let current = select(&toxics.borrow()).clone();
+        let mut blackhole = false;
+        for toxic in &current {
+            match toxic {
+                V1Toxic::Latency { latency_ms, jitter_ms } => {
+                    let jitter = rand::random::<u64>()
+                        % jitter_ms.saturating_add(1);
+                    tokio::time::sleep(Duration::from_millis(
+                        latency_ms.saturating_add(jitter))).await;
+                },
+                V1Toxic::Bandwidth { bytes_per_s } => {
+                    let rate = (*bytes_per_s).max(1) as f64;
+                    tokio::time::sleep(
+                        Duration::from_secs_f64(len as f64 / rate)).await;
+                },
+                V1Toxic::ResetPeer => return Err(Reset),
+                V1Toxic::Blackhole => blackhole = true,
+            }
+        }
+        if !blackhole && writer.write_all(&buf[..len]).await.is_err() {
+            return Err(Reset);
+        }
+    }
+}
+
+//Dropping a socket with zero linger time resets the connection.
+fn reset_on_drop(stream: &TcpStream) {
+    let _ = SockRef::from(stream).set_linger(Some(Duration::ZERO));
+}
+
+async fn handle_connection(ctx: &Context, target: u16,
+    mut client: TcpStream) {
+    let sender = ctx.proxies.toxics.get(&target)
+        .expect("Toxics for proxy not found");
+    if is_reset(&sender.borrow().upstream)
+        || is_reset(&sender.borrow().downstream) {
+        reset_on_drop(&client);
+        return;
+    }
+
+    let mut upstream = match connect_local(target).await {
+        Ok(upstream) => upstream,
+        Err(e) => {
+            log::warn!("Unable to connect to proxied port {target}: {e}");
+            return;
+        }
+    };
+
+    let res = {
+        let (mut client_read, mut client_write) = client.split();
+        let (mut upstream_read, mut upstream_write) = upstream.split();
+        futures::future::try_join(
+            pump(&mut client_read, &mut upstream_write, sender.subscribe(),
+                |t| &t.upstream),
+            pump(&mut upstream_read, &mut client_write, sender.subscribe(),
+                |t| &t.downstream),
+        ).await
+    };
+
+    if res.is_err() {
+        reset_on_drop(&client);
+        reset_on_drop(&upstream);
+    }
+}
+
+async fn serve_proxy(ctx: &Context, target: u16, listener: TcpListener) {
+    let mut connections = FuturesUnordered::new();
+    loop {
+        futures::select! {
+            res = listener.accept().fuse() => match res {
+                Ok((stream, _)) => {
+                    connections.push(handle_connection(ctx, target, stream));
+                },
+                Err(e) => log::warn!("Unable to accept connection: {e}"),
+            },
+            _ = connections.select_next_some() => (),
+        }
+    }
+}
+
+pub async fn serve(ctx: &Context, sender: Sender<V1Event>) {
+    let mut futures = Vec::new();
+    for proxy in &ctx.setup.proxies {
+        let listen_addr = format!("[::]:{}", proxy.listen);
+        match TcpListener::bind(&listen_addr).await {
+            Ok(listener) => futures.push(
+                serve_proxy(ctx, proxy.target, listener)),
+            Err(e) => {
+                let message = format!("Unable to listen on {listen_addr}: {e}");
+                log::error!("{message}");
+                sender.send(V1Event::InternalError(message)).await
+                    .expect("Cannot send event");
+            },
+        }
+    }
+
+    futures::future::join_all(futures).await;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    async fn pump_with(toxics: Vec<V1Toxic>, data: &[u8])
+    -> (Result<(), Reset>, Vec<u8>) {
+        let (sender, receiver) = watch::channel(V1Toxics {
+            upstream: toxics,
+            downstream: Vec::new(),
+        });
+        let mut input = data;
+        let mut output = Vec::new();
+        let res = pump(&mut input, &mut output, receiver,
+            |t| &t.upstream).await;
+        drop(sender);
+        (res, output)
+    }
+
+    #[tokio::test]
+    async fn without_toxics_data_is_copied() {
+        let (res, output) = pump_with(vec![], b"hello").await;
+        assert!(res.is_ok());
+        assert_eq!(output, b"hello");
+    }
+
+    #[tokio::test]
+    async fn blackhole_drops_data() {
+        let (res, output) = pump_with(vec![V1Toxic::Blackhole], b"hello").await;
+        assert!(res.is_ok());
+        assert!(output.is_empty());
+    }
+
+    #[tokio::test]
+    async fn reset_peer_stops_copying() {
+        let (res, output) = pump_with(vec![V1Toxic::ResetPeer], b"hello").await;
+        assert!(res.is_err());
+        assert!(output.is_empty());
+    }
+
+    #[tokio::test]
+    async fn huge_latency_does_not_overflow() {
+        let res = tokio::time::timeout(Duration::from_millis(10),
+            pump_with(vec![V1Toxic::Latency {
+                latency_ms: u64::MAX, jitter_ms: u64::MAX
+            }], b"hello")).await;
+        assert!(res.is_err());
+    }
+
+    #[tokio::test]
+    async fn latency_delays_data() {
+        let start = tokio::time::Instant::now();
+        let (res, output) = pump_with(vec![V1Toxic::Latency {
+            latency_ms: 100, jitter_ms: 0
+        }], b"hello").await;
+        assert!(res.is_ok());
+        assert_eq!(output, b"hello");
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+}
@@ -0,0 +1,264 @@
+/*
+ * Copyright 2024 Akash Rawal
+ *
+ * This file is part of Disposables.
+ *
+ * Disposables is free software: you can redistribute it and/or modify it under 
+ * the terms of the GNU General Public License as published by the 
+ * Free Software Foundation, either version 3 of the License, or 
+ * (at your option) any later version.
+ * 
+ * Disposables is distributed in the hope that it will be useful, 
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of 
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. 
+ * See the GNU General Public License for more details.
+ * 
+ * You should have received a copy of the GNU General Public License 
+ * along with Disposables. If not, see <https://www.gnu.org/licenses/>. 
+ */
+//Pseudo-terminal for the entrypoint
+
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::io::unix::AsyncFd;
+use tokio::process::Command;
+
+use disposables_protocol::V1TermSize;
+
+//End-of-file character of the terminal (Ctrl-D)
+const EOT: u8 = 4;
+
+fn winsize(size: V1TermSize) -> libc::winsize {
+    libc::winsize {
+        ws_row: size.rows,
+        ws_col: size.cols,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    }
+}
+
+fn check(res: libc::c_int) -> std::io::Result<libc::c_int> {
+    if res == -1 {
+        Err(std::io::Error::last_os_error())
+    } else {
+        Ok(res)
+    }
+}
+
+//Both sides of a newly opened pseudo-terminal
+pub struct Pty {
+    pub master: OwnedFd,
+    pub slave: OwnedFd,
+}
+
+pub fn open(size: V1TermSize) -> std::io::Result<Pty> {
+    let (mut master, mut slave) = (-1, -1);
+    let winsize = winsize(size);
+    //SAFETY: openpty() only writes to the given pointers, name is not used.
+    unsafe {
+        check(libc::openpty(&mut master, &mut slave, std::ptr::null_mut(),
+            std::ptr::null(), &winsize))?;
+        Ok(Pty {
+            master: OwnedFd::from_raw_fd(master),
+            slave: OwnedFd::from_raw_fd(slave),
+        })
+    }
+}
+
+pub fn resize(master: &impl AsRawFd, size: V1TermSize) -> std::io::Result<()> {
+    let winsize = winsize(size);
+    //SAFETY: TIOCSWINSZ only reads the given winsize struct.
+    check(unsafe {
+        libc::ioctl(master.as_raw_fd(), libc::TIOCSWINSZ, &winsize)
+    }).map(drop)
+}
+
+//Makes the terminal on the child's stdin its controlling terminal, so that
+//the child gets job control and signals like a process on a terminal.
+fn set_controlling_terminal(command: &mut Command) {
+    //SAFETY: Only async-signal-safe functions are called after fork.
+    unsafe {
+        command.pre_exec(|| {
+            check(libc::setsid())?;
+            check(libc::ioctl(0, libc::TIOCSCTTY, 0))?;
+            Ok(())
+        });
+    }
+}
+
+//Opens a pseudo-terminal for the command to run on, and returns its master
+//side. The command must be dropped after spawning it, so that the output
+//ends when the child exits.
+pub fn attach(command: &mut Command, size: V1TermSize)
+-> std::io::Result<OwnedFd> {
+    let pty = open(size)?;
+    command.stdin(pty.slave.try_clone()?)
+        .stdout(pty.slave.try_clone()?)
+        .stderr(pty.slave);
+    set_controlling_terminal(command);
+    Ok(pty.master)
+}
+
+//Asynchronous access to the master side of a pseudo-terminal
+pub struct PtyMaster {
+    fd: AsyncFd<File>,
+    //Whether the input written so far ends with a complete line
+    line_complete: bool,
+}
+
+impl PtyMaster {
+    pub fn new(fd: &OwnedFd) -> std::io::Result<Self> {
+        let fd = fd.try_clone()?;
+        //SAFETY: fcntl() is called on a file descriptor we own.
+        unsafe {
+            let flags = check(libc::fcntl(fd.as_raw_fd(), libc::F_GETFL))?;
+            check(libc::fcntl(fd.as_raw_fd(), libc::F_SETFL,
+                flags | libc::O_NONBLOCK))?;
+        }
+        Ok(Self { fd: AsyncFd::new(File::from(fd))?, line_complete: true })
+    }
+}
+
+impl AsyncRead for PtyMaster {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        loop {
+            let mut guard = ready!(self.fd.poll_read_ready(cx))?;
+            let unfilled = buf.initialize_unfilled();
+            match guard.try_io(|fd| fd.get_ref().read(unfilled)) {
+                Ok(Ok(len)) => {
+                    buf.advance(len);
+                    return Poll::Ready(Ok(()));
+                },
+                //Linux reports EIO once all processes have closed
+                //the slave side, which is the end of the output.
+                Ok(Err(e)) if e.raw_os_error() == Some(libc::EIO) => {
+                    return Poll::Ready(Ok(()));
+                },
+                Ok(Err(e)) => return Poll::Ready(Err(e)),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+impl AsyncWrite for PtyMaster {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8])
+    -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        loop {
+            let mut guard = ready!(this.fd.poll_write_ready(cx))?;
+            match guard.try_io(|fd| fd.get_ref().write(buf)) {
+                Ok(res) => {
+                    if let Ok(len @ 1..) = res {
+                        this.line_complete = buf[len - 1] == b'\n';
+                    }
+                    return Poll::Ready(res);
+                },
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>)
+    -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    //The terminal cannot be closed for the entrypoint, so end-of-file is
+    //sent instead. The terminal only takes it as end-of-file at the start
+    //of a line, so an incomplete last line is ended first.
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>)
+    -> Poll<std::io::Result<()>> {
+        while !self.line_complete {
+            ready!(self.as_mut().poll_write(cx, b"\n"))?;
+        }
+        self.poll_write(cx, &[EOT]).map_ok(drop)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    const SIZE: V1TermSize = V1TermSize { rows: 24, cols: 80 };
+
+    #[tokio::test]
+    async fn output_of_child_is_read_until_it_exits() {
+        let mut command = Command::new("sh");
+        command.args(["-c", "test -t 1 && echo tty"]);
+        let master = attach(&mut command, SIZE).unwrap();
+        let mut child = command.spawn().unwrap();
+        drop(command);
+
+        let mut master = PtyMaster::new(&master).unwrap();
+        let mut output = String::new();
+        master.read_to_string(&mut output).await.unwrap();
+        assert_eq!(output, "tty\r\n");
+        assert!(child.wait().await.unwrap().success());
+    }
+
+    #[tokio::test]
+    async fn input_is_read_by_child_and_shutdown_sends_eof() {
+        let mut command = Command::new("sh");
+        command.args(["-c", "stty -echo; wc -c"]);
+        let master = attach(&mut command, SIZE).unwrap();
+        let mut child = command.spawn().unwrap();
+        drop(command);
+
+        let mut writer = PtyMaster::new(&master).unwrap();
+        let mut reader = PtyMaster::new(&master).unwrap();
+        drop(master);
+        //Let stty take effect before writing
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        writer.write_all(b"abc\n").await.unwrap();
+        writer.shutdown().await.unwrap();
+
+        let mut output = String::new();
+        reader.read_to_string(&mut output).await.unwrap();
+        assert_eq!(output.trim(), "4");
+        assert!(child.wait().await.unwrap().success());
+    }
+
+    #[tokio::test]
+    async fn shutdown_ends_incomplete_line() {
+        let mut command = Command::new("sh");
+        command.args(["-c", "stty -echo; wc -l"]);
+        let master = attach(&mut command, SIZE).unwrap();
+        let mut child = command.spawn().unwrap();
+        drop(command);
+
+        let mut writer = PtyMaster::new(&master).unwrap();
+        let mut reader = PtyMaster::new(&master).unwrap();
+        drop(master);
+        //Let stty take effect before writing
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        writer.write_all(b"abc\ndef").await.unwrap();
+        writer.shutdown().await.unwrap();
+
+        let mut output = String::new();
+        reader.read_to_string(&mut output).await.unwrap();
+        assert_eq!(output.trim(), "2");
+        assert!(child.wait().await.unwrap().success());
+    }
+
+    #[tokio::test]
+    async fn terminal_can_be_resized() {
+        let pty = open(SIZE).unwrap();
+        resize(&pty.master, V1TermSize { rows: 10, cols: 20 }).unwrap();
+
+        let mut winsize = winsize(SIZE);
+        //SAFETY: TIOCGWINSZ only writes to the given winsize struct.
+        check(unsafe {
+            libc::ioctl(pty.slave.as_raw_fd(), libc::TIOCGWINSZ, &mut winsize)
+        }).unwrap();
+        assert_eq!((winsize.ws_row, winsize.ws_col), (10, 20));
+    }
+}
@@ -18,21 +18,57 @@
  */
 //Ready/timeout state tracker
 
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 
-use disposables_protocol::V1Event;
+use disposables_protocol::{V1Certificate, V1Event};
 use tokio::sync::mpsc::Sender;
+use tokio::sync::watch;
+
+//What becomes ready when all the conditions are satisfied
+enum Scope {
+    Entrypoint,
+    Sidecar(String),
+    //Phases are reported in order by the caller, see `main::check_phases`
+    Phase(String),
+}
 
 pub struct ReadySignal {
     value: RefCell<i32>,
+    //Whether the outcome (ready, timeout or failure) has been sent
+    settled: Cell<bool>,
     sender: Sender<V1Event>,
+    ready: watch::Sender<bool>,
+    scope: Scope,
+    //Ports found listening by a Listening condition
+    listening: RefCell<Option<Vec<u16>>>,
+    //Certificates presented to Tls conditions
+    certificates: RefCell<Vec<V1Certificate>>,
 }
 
 impl ReadySignal {
     pub fn new(value: i32, sender:Sender<V1Event>) -> Self {
         Self {
             value: RefCell::new(value),
-            sender
+            settled: Cell::new(false),
+            sender,
+            ready: watch::Sender::new(value == 0),
+            scope: Scope::Entrypoint,
+            listening: RefCell::new(None),
+            certificates: RefCell::new(Vec::new()),
+        }
+    }
+    pub fn for_sidecar(value: i32, sender: Sender<V1Event>, name: String)
+    -> Self {
+        Self {
+            scope: Scope::Sidecar(name),
+            ..Self::new(value, sender)
+        }
+    }
+    pub fn for_phase(value: i32, sender: Sender<V1Event>, name: String)
+    -> Self {
+        Self {
+            scope: Scope::Phase(name),
+            ..Self::new(value, sender)
         }
     }
     pub async fn dec(&self, by: i32) {
@@ -43,11 +79,70 @@ impl ReadySignal {
                 *value
             };
             if value == 0 {
-                self.sender.send(V1Event::Ready).await
-                    .expect("Cannot send event");
+                let event = match &self.scope {
+                    Scope::Entrypoint => {
+                        log::info!("All wait conditions satisfied, \
+                            container is ready");
+                        let listening = self.listening.borrow_mut().take();
+                        if let Some(ports) = listening {
+                            self.sender.send(V1Event::Listening(ports)).await
+                                .expect("Cannot send event");
+                        }
+                        let certificates = self.certificates.take();
+                        if !certificates.is_empty() {
+                            self.sender
+                                .send(V1Event::TlsCertificates(certificates))
+                                .await.expect("Cannot send event");
+                        }
+                        Some(V1Event::Ready)
+                    },
+                    Scope::Sidecar(name) => {
+                        log::info!("All wait conditions satisfied, \
+                            sidecar {name} is ready");
+                        Some(V1Event::SidecarReady(name.clone()))
+                    },
+                    Scope::Phase(name) => {
+                        log::info!("All wait conditions of phase {name} \
+                            satisfied");
+                        None
+                    },
+                };
+                self.settled.set(true);
+                if let Some(event) = event {
+                    self.sender.send(event).await
+                        .expect("Cannot send event");
+                }
+                self.ready.send_replace(true);
             }
         }
     }
+    //Records the ports found by a Listening condition, to be reported
+    //along with readiness.
+    pub fn set_listening(&self, ports: Vec<u16>) {
+        *self.listening.borrow_mut() = Some(ports);
+    }
+    //Records a certificate presented to a Tls condition, to be reported
+    //along with readiness.
+    pub fn add_certificate(&self, certificate: V1Certificate) {
+        self.certificates.borrow_mut().push(certificate);
+    }
+    //Moves the ports and certificates found while checking the conditions
+    //of a phase to the signal that reports them.
+    pub fn merge_into(&self, other: &ReadySignal) {
+        if let Some(ports) = self.listening.take() {
+            other.set_listening(ports);
+        }
+        other.certificates.borrow_mut()
+            .append(&mut self.certificates.borrow_mut());
+    }
+    //Whether the outcome has been sent
+    pub fn is_settled(&self) -> bool {
+        self.settled.get()
+    }
+    //Resolves once there is nothing left to wait for.
+    pub async fn wait_ready(&self) {
+        let _ = self.ready.subscribe().wait_for(|ready| *ready).await;
+    }
     pub async fn timeout(&self) {
         let prev_value = {
             let mut value = self.value.borrow_mut();
@@ -56,9 +151,30 @@ impl ReadySignal {
             prev_value
         };
         if prev_value > 0 {
-            self.sender.send(V1Event::FailedTimeout).await
-                .expect("Cannot send event");
+            log::warn!("Timed out with {prev_value} wait condition(s) pending");
+            let event = match &self.scope {
+                Scope::Entrypoint => Some(V1Event::FailedTimeout),
+                Scope::Sidecar(name) =>
+                    Some(V1Event::SidecarFailedTimeout(name.clone())),
+                //Reported by the entrypoint's signal
+                Scope::Phase(_) => None,
+            };
+            self.settled.set(true);
+            if let Some(event) = event {
+                self.sender.send(event).await
+                    .expect("Cannot send event");
+            }
+        }
+    }
+    //Sends a failure event, unless the outcome has already been sent.
+    pub async fn fail(&self, event: V1Event) {
+        if self.settled.replace(true) {
+            return;
         }
+        *self.value.borrow_mut() = 0;
+        log::warn!("Readiness failed: {event:?}");
+        self.sender.send(event).await
+            .expect("Cannot send event");
     }
 }
 
@@ -118,4 +234,113 @@ mod test {
         assert!(matches!(receiver.recv().await, Some(V1Event::FailedTimeout)));
         assert!(receiver.recv().await.is_none());
     }
+
+    #[tokio::test]
+    async fn after_failure_ready_signal_cannot_be_sent() {
+        let (sender, mut receiver) = channel(1);
+        let s = ReadySignal::new(1, sender);
+        s.fail(V1Event::FailedTimeout).await;
+        s.dec(1).await;
+        s.timeout().await;
+        drop(s);
+        assert!(matches!(receiver.recv().await, Some(V1Event::FailedTimeout)));
+        assert!(receiver.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn after_ready_signal_failure_cannot_be_sent() {
+        let (sender, mut receiver) = channel(1);
+        let s = ReadySignal::new(1, sender);
+        s.dec(1).await;
+        s.fail(V1Event::FailedTimeout).await;
+        drop(s);
+        assert!(matches!(receiver.recv().await, Some(V1Event::Ready)));
+        assert!(receiver.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn when_wait_for_list_is_empty_then_failure_can_be_sent() {
+        let (sender, mut receiver) = channel(1);
+        let s = ReadySignal::new(0, sender);
+        s.fail(V1Event::FailedTimeout).await;
+        drop(s);
+        assert!(matches!(receiver.recv().await, Some(V1Event::FailedTimeout)));
+        assert!(receiver.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn listening_ports_are_sent_before_ready_signal() {
+        let (sender, mut receiver) = channel(2);
+        let s = ReadySignal::new(1, sender);
+        s.set_listening(vec![8080]);
+        s.dec(1).await;
+        drop(s);
+        assert!(matches!(receiver.recv().await,
+            Some(V1Event::Listening(ports)) if ports == [8080]));
+        assert!(matches!(receiver.recv().await, Some(V1Event::Ready)));
+        assert!(receiver.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn certificates_are_sent_before_ready_signal() {
+        let (sender, mut receiver) = channel(2);
+        let s = ReadySignal::new(2, sender);
+        let certificate = V1Certificate {
+            port: 443,
+            subject: "CN=localhost".into(),
+            not_after: 0,
+            sha256: "00".into(),
+        };
+        s.add_certificate(certificate.clone());
+        s.dec(1).await;
+        s.dec(1).await;
+        drop(s);
+        assert!(matches!(receiver.recv().await,
+            Some(V1Event::TlsCertificates(certs)) if certs == [certificate]));
+        assert!(matches!(receiver.recv().await, Some(V1Event::Ready)));
+        assert!(receiver.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn phase_signal_hands_over_its_reports() {
+        let (sender, mut receiver) = channel(2);
+        let ready = ReadySignal::new(1, sender.clone());
+        let phase = ReadySignal::for_phase(1, sender, "server".into());
+        phase.set_listening(vec![8080]);
+        phase.dec(1).await;
+        phase.wait_ready().await;
+        phase.merge_into(&ready);
+        ready.dec(1).await;
+        drop(ready);
+        drop(phase);
+        assert!(matches!(receiver.recv().await,
+            Some(V1Event::Listening(ports)) if ports == [8080]));
+        assert!(matches!(receiver.recv().await, Some(V1Event::Ready)));
+        assert!(receiver.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn sidecar_signals_carry_its_name() {
+        let (sender, mut receiver) = channel(2);
+        let ready = ReadySignal::for_sidecar(1, sender.clone(), "smtp".into());
+        ready.dec(1).await;
+        let timeout = ReadySignal::for_sidecar(1, sender, "smtp".into());
+        timeout.timeout().await;
+        drop((ready, timeout));
+        assert!(matches!(receiver.recv().await,
+            Some(V1Event::SidecarReady(name)) if name == "smtp"));
+        assert!(matches!(receiver.recv().await,
+            Some(V1Event::SidecarFailedTimeout(name)) if name == "smtp"));
+        assert!(receiver.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn wait_ready_resolves_after_ready_signal() {
+        let (sender, mut receiver) = channel(1);
+        let s = ReadySignal::new(1, sender);
+        futures::join!(s.wait_ready(), async {
+            s.dec(1).await;
+        });
+        assert!(matches!(receiver.recv().await, Some(V1Event::Ready)));
+    }
 }
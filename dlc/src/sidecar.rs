@@ -0,0 +1,153 @@
+/*
+ * Copyright 2024 Akash Rawal
+ *
+ * This file is part of Disposables.
+ *
+ * Disposables is free software: you can redistribute it and/or modify it under 
+ * the terms of the GNU General Public License as published by the 
+ * Free Software Foundation, either version 3 of the License, or 
+ * (at your option) any later version.
+ * 
+ * Disposables is distributed in the hope that it will be useful, 
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of 
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. 
+ * See the GNU General Public License for more details.
+ * 
+ * You should have received a copy of the GNU General Public License 
+ * along with Disposables. If not, see <https://www.gnu.org/licenses/>. 
+ */
+//Sidecar processes run alongside the entrypoint
+
+use std::time::Duration;
+use std::process::Stdio;
+
+use futures::FutureExt;
+use futures::future::FusedFuture;
+use tokio::io::{AsyncBufRead, BufReader};
+use tokio::process::Command;
+use tokio::sync::mpsc::Sender;
+
+use disposables_protocol::{V1Event, V1Sidecar};
+
+use crate::{Context, OUTPUT_DRAIN_TIMEOUT};
+use crate::{check_conditions, logger, match_patterns, output};
+use crate::stdout_patterns;
+use crate::output::LineReader;
+use crate::ready::ReadySignal;
+
+async fn scan_output(ctx: &Context, name: &str, tag: &str,
+    stream: &mut (impl AsyncBufRead + Unpin),
+    mut patterns: Vec<&String>, ready_signal: &ReadySignal) {
+    let source = format!("stdout of sidecar {name}");
+    let mut reader = LineReader::new(stream, ctx.setup.max_line_length);
+    loop {
+        let line = match reader.read_line().await {
+            Ok(Some(line)) => line,
+            Ok(None) => break,
+            Err(e) => {
+                log::warn!("Cannot read {tag} of sidecar {name}: {e}");
+                break;
+            },
+        };
+        let line = output::decode(&line.bytes);
+        logger::output(tag, &line);
+
+        match_patterns(&mut patterns, &line, &source, ready_signal).await;
+    }
+}
+
+async fn run(ctx: &Context, sidecar: &V1Sidecar, sender: &Sender<V1Event>) {
+    let name = &sidecar.name;
+    let spawn_res = match sidecar.argv.split_first() {
+        Some((arg0, args)) => Command::new(arg0).args(args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| e.to_string()),
+        None => Err("Command is empty".to_owned()),
+    };
+    let mut child = match spawn_res {
+        Ok(child) => child,
+        Err(error) => {
+            log::error!("Failed to start sidecar {name}: {error}");
+            sender.send(V1Event::SidecarFailedToStart {
+                name: name.clone(),
+                error,
+            }).await.expect("Cannot send event");
+            return;
+        },
+    };
+    let pid = child.id();
+    log::info!("Started sidecar {name} with PID {}", pid.unwrap_or_default());
+
+    let stdout_tag = sidecar.stdout_tag.clone()
+        .unwrap_or_else(|| format!("{name}.out"));
+    let stderr_tag = sidecar.stderr_tag.clone()
+        .unwrap_or_else(|| format!("{name}.err"));
+    let mut stdout = BufReader::new(child.stdout.take()
+        .expect("stdout of sidecar is None"));
+    let mut stderr = BufReader::new(child.stderr.take()
+        .expect("stderr of sidecar is None"));
+
+    let ready_signal = ReadySignal::for_sidecar(sidecar.wait_for.len() as i32,
+        sender.clone(), name.clone());
+
+    let stdout_patterns = stdout_patterns(&sidecar.wait_for);
+
+    let output = async {
+        futures::join!{
+            scan_output(ctx, name, &stdout_tag, &mut stdout, stdout_patterns,
+                &ready_signal),
+            scan_output(ctx, name, &stderr_tag, &mut stderr, Vec::new(),
+                &ready_signal),
+        };
+    }.fuse();
+    futures::pin_mut!(output);
+
+    futures::select!{
+        _ = child.wait().fuse() => (),
+        _ = async {
+            output.as_mut().await;
+            futures::future::pending::<()>().await;
+        }.fuse() => (),
+        _ = async {
+            futures::join!{
+                check_conditions(ctx, &sidecar.wait_for, pid, &ready_signal),
+                async {
+                    let dur = Duration::from_secs(ctx.setup.ready_timeout_s);
+                    tokio::time::sleep(dur).await;
+
+                    ready_signal.timeout().await;
+                },
+            };
+            futures::future::pending::<()>().await;
+        }.fuse() => (),
+    };
+
+    let wait_res = match child.wait().await {
+        Ok(wait_res) => wait_res,
+        Err(e) => {
+            let message = format!("Failed to wait for sidecar {name}: {e}");
+            log::error!("{message}");
+            sender.send(V1Event::InternalError(message)).await
+                .expect("Cannot send event");
+            return;
+        },
+    };
+    log::info!("Sidecar {name} exited: {wait_res}");
+    if !output.is_terminated() {
+        let _ = tokio::time::timeout(OUTPUT_DRAIN_TIMEOUT, output).await;
+    }
+    sender.send(V1Event::SidecarExited {
+        name: name.clone(),
+        code: wait_res.code(),
+    }).await.expect("Cannot send event");
+}
+
+//Runs all sidecars till they exit.
+pub async fn run_all(ctx: &Context, sender: &Sender<V1Event>) {
+    futures::future::join_all(ctx.setup.sidecars.iter()
+        .map(|sidecar| run(ctx, sidecar, sender))).await;
+}
@@ -0,0 +1,127 @@
+/*
+ * Copyright 2024 Akash Rawal
+ *
+ * This file is part of Disposables.
+ *
+ * Disposables is free software: you can redistribute it and/or modify it under 
+ * the terms of the GNU General Public License as published by the 
+ * Free Software Foundation, either version 3 of the License, or 
+ * (at your option) any later version.
+ * 
+ * Disposables is distributed in the hope that it will be useful, 
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of 
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. 
+ * See the GNU General Public License for more details.
+ * 
+ * You should have received a copy of the GNU General Public License 
+ * along with Disposables. If not, see <https://www.gnu.org/licenses/>. 
+ */
+//Following the lines appended to a file
+
+use std::io::{ErrorKind, Read, Seek, SeekFrom};
+use std::path::PathBuf;
+
+use crate::output;
+
+pub struct Tail {
+    path: PathBuf,
+    //Position up to which the file has been read
+    offset: u64,
+    //Incomplete last line
+    partial: Vec<u8>,
+    max_len: usize,
+}
+
+impl Tail {
+    pub fn new(path: impl Into<PathBuf>, max_len: usize) -> Self {
+        Self {
+            path: path.into(),
+            offset: 0,
+            partial: Vec::new(),
+            max_len,
+        }
+    }
+
+    //Returns the complete lines added to the file since the last call,
+    //starting from the beginning of the file. Lines longer than max_len
+    //bytes are split. A missing file has no lines.
+    pub fn read_lines(&mut self) -> std::io::Result<Vec<String>> {
+        let mut file = match std::fs::File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        //The file was truncated or replaced, e.g. by log rotation
+        if file.metadata()?.len() < self.offset {
+            self.offset = 0;
+            self.partial.clear();
+        }
+        file.seek(SeekFrom::Start(self.offset))?;
+        let read = file.read_to_end(&mut self.partial)?;
+        self.offset += read as u64;
+
+        let mut lines = Vec::new();
+        loop {
+            let window = &self.partial[..self.partial.len().min(self.max_len + 1)];
+            let end = match window.iter().position(|b| *b == b'\n') {
+                Some(pos) => pos + 1,
+                None if self.partial.len() >= self.max_len => self.max_len,
+                None => break,
+            };
+            let line = self.partial.drain(..end).collect::<Vec<_>>();
+            lines.push(output::decode(&line));
+        }
+        Ok(lines)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir()
+            .join(format!("dlc-tail-{}-{name}", std::process::id()))
+    }
+
+    #[test]
+    fn appended_lines_are_read_once() {
+        let path = temp_path("append");
+        let mut tail = Tail::new(&path, 100);
+        assert!(tail.read_lines().unwrap().is_empty());
+
+        let mut file = std::fs::File::create(&path).unwrap();
+        file.write_all(b"one\ntw").unwrap();
+        assert_eq!(tail.read_lines().unwrap(), ["one"]);
+        file.write_all(b"o\nthree\n").unwrap();
+        assert_eq!(tail.read_lines().unwrap(), ["two", "three"]);
+        assert!(tail.read_lines().unwrap().is_empty());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn truncated_file_is_read_from_the_beginning() {
+        let path = temp_path("truncate");
+        let mut tail = Tail::new(&path, 100);
+        std::fs::write(&path, "first line\n").unwrap();
+        assert_eq!(tail.read_lines().unwrap(), ["first line"]);
+
+        std::fs::write(&path, "new\n").unwrap();
+        assert_eq!(tail.read_lines().unwrap(), ["new"]);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn long_lines_are_split() {
+        let path = temp_path("split");
+        let mut tail = Tail::new(&path, 4);
+        std::fs::write(&path, "abcdefgh\nij").unwrap();
+        assert_eq!(tail.read_lines().unwrap(), ["abcd", "efgh"]);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,392 @@
+/*
+ * Copyright 2024 Akash Rawal
+ *
+ * This file is part of Disposables.
+ *
+ * Disposables is free software: you can redistribute it and/or modify it under 
+ * the terms of the GNU General Public License as published by the 
+ * Free Software Foundation, either version 3 of the License, or 
+ * (at your option) any later version.
+ * 
+ * Disposables is distributed in the hope that it will be useful, 
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of 
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. 
+ * See the GNU General Public License for more details.
+ * 
+ * You should have received a copy of the GNU General Public License 
+ * along with Disposables. If not, see <https://www.gnu.org/licenses/>. 
+ */
+//Requests and tunnelled connections on the DLC port
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::Duration;
+
+use futures::{FutureExt, StreamExt};
+use futures::stream::FuturesUnordered;
+use tokio::net::{TcpListener, TcpStream, UnixStream};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc::Sender;
+
+use disposables_protocol::{V1Event, V1Reply, V1Request};
+
+use crate::{Context, EntrypointStdin};
+use crate::pdu::{read_pdu, write_pdu};
+use crate::pty;
+
+pub async fn connect_local(port: u16) -> Result<TcpStream, std::io::Error> {
+    match TcpStream::connect((IpAddr::from(Ipv4Addr::LOCALHOST), port)).await {
+        Ok(stream) => Ok(stream),
+        Err(_) => {
+            TcpStream::connect((IpAddr::from(Ipv6Addr::LOCALHOST), port)).await
+        }
+    }
+}
+
+//Reverse tunnelled connections waiting to be picked up by the client
+#[derive(Default)]
+pub struct PendingTunnels {
+    next_id: RefCell<u64>,
+    streams: RefCell<HashMap<u64, TcpStream>>,
+}
+
+impl PendingTunnels {
+    fn add(&self, stream: TcpStream) -> u64 {
+        let id = {
+            let mut next_id = self.next_id.borrow_mut();
+            *next_id += 1;
+            *next_id
+        };
+        self.streams.borrow_mut().insert(id, stream);
+        id
+    }
+
+    fn take(&self, id: u64) -> Option<TcpStream> {
+        self.streams.borrow_mut().remove(&id)
+    }
+}
+
+//Compares tokens in constant time, so that the comparison does not reveal
+//how much of the token was guessed correctly.
+fn tokens_equal(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len()
+        && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+//Checks the token the client sends at the beginning of every connection.
+async fn authenticate(ctx: &Context, stream: &mut TcpStream)
+-> Result<(), String> {
+    let Some(token) = &ctx.setup.token else {
+        return Ok(());
+    };
+    match read_pdu(stream).await {
+        Ok(V1Request::Auth(t)) if tokens_equal(t.as_bytes(), token.as_bytes())
+            => Ok(()),
+        Ok(_) => Err("Invalid token".to_owned()),
+        Err(e) => Err(format!("Unable to read token: {e}")),
+    }
+}
+
+async fn splice(stream: &mut TcpStream,
+    reply: Result<impl AsyncRead + AsyncWrite + Unpin, String>) {
+    match reply {
+        Ok(mut target) => {
+            if write_pdu(stream, &V1Reply::Ok).await.is_ok() {
+                let _ = tokio::io::copy_bidirectional(stream, &mut target)
+                    .await;
+            }
+        },
+        Err(e) => {
+            let _ = write_pdu(stream, &V1Reply::Error(e)).await;
+        }
+    }
+}
+
+async fn feed_stdin(stream: &mut TcpStream,
+    stdin: Result<EntrypointStdin, String>) {
+    match stdin {
+        Ok(mut stdin) => {
+            if write_pdu(stream, &V1Reply::Ok).await.is_ok() {
+                if let Err(e) = tokio::io::copy(stream, &mut stdin).await {
+                    log::warn!("Unable to write to stdin of entrypoint: {e}");
+                }
+            }
+            //Signals end of input, before the connection is closed
+            let _ = stdin.shutdown().await;
+        },
+        Err(e) => {
+            let _ = write_pdu(stream, &V1Reply::Error(e)).await;
+        }
+    }
+}
+
+async fn handle_connection(ctx: &Context, mut stream: TcpStream) {
+    let timeout = Duration::from_secs(ctx.setup.client_timeout_s);
+    let request = match tokio::time::timeout(timeout, async {
+        authenticate(ctx, &mut stream).await?;
+        read_pdu(&mut stream).await
+            .map_err(|e| format!("Unable to read request: {e}"))
+    }).await {
+        Ok(Ok(request)) => request,
+        Ok(Err(e)) => {
+            log::warn!("{e}");
+            return;
+        },
+        Err(_) => {
+            log::warn!("Timeout occured while waiting for request");
+            return;
+        }
+    };
+
+    let target = match request {
+        V1Request::Connect(port) => connect_local(port).await
+            .map_err(|e| format!("Unable to connect to port {port}: {e}")),
+        V1Request::ConnectUnix(path) => {
+            let target = UnixStream::connect(&path).await
+                .map_err(|e| format!("Unable to connect to {path}: {e}"));
+            splice(&mut stream, target).await;
+            return;
+        },
+        V1Request::Accept(id) => ctx.pending_tunnels.take(id)
+            .ok_or_else(|| format!("No pending connection with ID {id}")),
+        V1Request::SetToxics { port, toxics } => {
+            let reply = match ctx.proxies.set_toxics(port, toxics) {
+                Ok(()) => V1Reply::Ok,
+                Err(e) => V1Reply::Error(e),
+            };
+            let _ = write_pdu(&mut stream, &reply).await;
+            return;
+        },
+        V1Request::Attach => {
+            if write_pdu(&mut stream, &V1Reply::Ok).await.is_ok() {
+                let _ = ctx.attach_sender.send(stream).await;
+            }
+            return;
+        },
+        V1Request::Stdin => {
+            let receiver = ctx.stdin.borrow_mut().take();
+            let stdin = match receiver {
+                _ if !ctx.setup.stdin => Err("Stdin of the entrypoint \
+                    is not piped".to_owned()),
+                Some(receiver) => receiver.await
+                    .map_err(|_| "Entrypoint was not started".to_owned()),
+                None => Err("Stdin is already in use".to_owned()),
+            };
+            feed_stdin(&mut stream, stdin).await;
+            return;
+        },
+        V1Request::Resize(size) => {
+            let reply = match &*ctx.pty_master.borrow() {
+                Some(master) => pty::resize(master, size)
+                    .map(|_| V1Reply::Ok)
+                    .unwrap_or_else(|e| V1Reply::Error(
+                        format!("Unable to resize terminal: {e}"))),
+                None => V1Reply::Error("Entrypoint is not running on a \
+                    pseudo-terminal".to_owned()),
+            };
+            let _ = write_pdu(&mut stream, &reply).await;
+            return;
+        },
+        V1Request::Keep(keep_s) => {
+            log::info!("Client asked to keep the container for {keep_s}s \
+                once it disconnects");
+            ctx.keep_s.set(Some(keep_s));
+            let _ = write_pdu(&mut stream, &V1Reply::Ok).await;
+            return;
+        },
+        V1Request::Auth(_) => Err("Connection is already authenticated"
+            .to_owned()),
+    };
+    splice(&mut stream, target).await;
+}
+
+//Answers a connection when DLC cannot serve anything because of `error`.
+//The control connection gets the error as an event, other connections get
+//it as the reply to their request. The token is unknown at this point, so
+//it is skipped rather than checked.
+pub async fn refuse(ctx: &Context, mut stream: TcpStream, error: &str,
+    control: bool) {
+    if control {
+        let event = V1Event::InternalError(error.to_owned());
+        let _ = write_pdu(&mut stream, &event).await;
+        return;
+    }
+    let timeout = Duration::from_secs(ctx.setup.client_timeout_s);
+    if let Ok(Ok(request)) = tokio::time::timeout(timeout, async {
+        loop {
+            match read_pdu::<V1Request>(&mut stream).await? {
+                V1Request::Auth(_) => (),
+                request => break Ok::<_, std::io::Error>(request),
+            }
+        }
+    }).await {
+        log::warn!("Refusing request {request:?}: {error}");
+        let _ = write_pdu(&mut stream, &V1Reply::Error(error.to_owned())).await;
+    }
+}
+
+//Serves all connections to the DLC port after the control connection.
+pub async fn serve(ctx: &Context, listener: &TcpListener) {
+    let mut connections = FuturesUnordered::new();
+    loop {
+        futures::select! {
+            res = listener.accept().fuse() => match res {
+                Ok((stream, _)) => {
+                    connections.push(handle_connection(ctx, stream));
+                },
+                Err(e) => log::warn!("Unable to accept connection: {e}"),
+            },
+            _ = connections.select_next_some() => (),
+        }
+    }
+}
+
+async fn serve_reverse_port(ctx: &Context, port: u16, listener: TcpListener,
+    sender: &Sender<V1Event>) {
+    let timeout = Duration::from_secs(ctx.setup.client_timeout_s);
+    let mut expiring = FuturesUnordered::new();
+    loop {
+        futures::select! {
+            res = listener.accept().fuse() => {
+                let stream = match res {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        log::warn!("Unable to accept connection: {e}");
+                        continue;
+                    }
+                };
+                let id = ctx.pending_tunnels.add(stream);
+                sender.send(V1Event::TunnelRequested { port, id }).await
+                    .expect("Cannot send event");
+                //Drop the connection if the client does not pick it up
+                expiring.push(async move {
+                    tokio::time::sleep(timeout).await;
+                    if ctx.pending_tunnels.take(id).is_some() {
+                        log::warn!("Reverse tunnelled connection {id} on port \
+                            {port} was not picked up by the client");
+                    }
+                });
+            },
+            _ = expiring.select_next_some() => (),
+        }
+    }
+}
+
+//Listens on reverse tunnel ports and asks the client to pick up
+//each accepted connection.
+pub async fn serve_reverse(ctx: &Context, sender: Sender<V1Event>) {
+    let mut futures = Vec::new();
+    for port in &ctx.setup.reverse_tunnels {
+        let listen_addr = format!("[::]:{port}");
+        match TcpListener::bind(&listen_addr).await {
+            Ok(listener) => futures.push(
+                serve_reverse_port(ctx, *port, listener, &sender)),
+            Err(e) => {
+                let message = format!("Unable to listen on {listen_addr}: {e}");
+                log::error!("{message}");
+                sender.send(V1Event::InternalError(message)).await
+                    .expect("Cannot send event");
+            },
+        }
+    }
+
+    futures::future::join_all(futures).await;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::sync::mpsc::Receiver;
+    use crate::MySetupMsg;
+
+    #[test]
+    fn tokens_are_compared_exactly() {
+        assert!(tokens_equal(b"secret", b"secret"));
+        assert!(!tokens_equal(b"secret", b"secreT"));
+        assert!(!tokens_equal(b"secret", b"secret2"));
+        assert!(!tokens_equal(b"", b"secret"));
+    }
+
+    //Sends requests on a new connection and returns the reply, if any.
+    async fn send_requests(ctx: &Context, requests: Vec<V1Request>)
+    -> Option<V1Reply> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        for request in &requests {
+            write_pdu(&mut client, request).await.unwrap();
+        }
+        let (_, reply) = futures::join!(
+            handle_connection(ctx, stream),
+            read_pdu::<V1Reply>(&mut client),
+        );
+        reply.ok()
+    }
+
+    fn context_with_token() -> (Context, Receiver<TcpStream>) {
+        let (ctx, attach_receiver, _) = Context::new(MySetupMsg {
+            token: Some("secret".to_owned()),
+            stdin: true,
+            ..MySetupMsg::default()
+        }, "true".into(), Vec::new());
+        (ctx, attach_receiver)
+    }
+
+    #[tokio::test]
+    async fn requests_without_valid_token_are_refused() {
+        let (ctx, mut attach_receiver) = context_with_token();
+        for token in [None, Some("wrong")] {
+            for request in [V1Request::Connect(1), V1Request::Accept(1),
+                V1Request::Attach, V1Request::Stdin, V1Request::Keep(60)] {
+                let auth = token.map(|t| V1Request::Auth(t.to_owned()));
+                let requests = auth.into_iter().chain([request]).collect();
+                let reply = send_requests(&ctx, requests).await;
+                assert!(reply.is_none(), "Unexpected reply: {reply:?}");
+            }
+        }
+        assert!(attach_receiver.try_recv().is_err());
+        assert!(ctx.stdin.borrow().is_some());
+        assert_eq!(ctx.keep_s.get(), None);
+    }
+
+    #[tokio::test]
+    async fn requests_with_valid_token_are_served() {
+        let (ctx, mut attach_receiver) = context_with_token();
+        let auth = || V1Request::Auth("secret".to_owned());
+        for request in [V1Request::Connect(1), V1Request::Accept(1)] {
+            let reply = send_requests(&ctx, vec![auth(), request]).await;
+            //Neither request can succeed, but both get an answer
+            assert!(matches!(reply, Some(V1Reply::Error(_))),
+                "Unexpected reply: {reply:?}");
+        }
+        let reply = send_requests(&ctx, vec![auth(), V1Request::Keep(60)]).await;
+        assert!(matches!(reply, Some(V1Reply::Ok)),
+            "Unexpected reply: {reply:?}");
+        assert_eq!(ctx.keep_s.get(), Some(60));
+        let reply = send_requests(&ctx, vec![auth(), V1Request::Attach]).await;
+        assert!(matches!(reply, Some(V1Reply::Ok)),
+            "Unexpected reply: {reply:?}");
+        assert!(attach_receiver.try_recv().is_ok());
+    }
+
+    #[tokio::test]
+    async fn taken_port_is_reported() {
+        let taken = TcpListener::bind("[::]:0").await.unwrap();
+        let port = taken.local_addr().unwrap().port();
+        let (ctx, _, _) = Context::new(MySetupMsg {
+            reverse_tunnels: vec![port],
+            ..MySetupMsg::default()
+        }, "true".into(), Vec::new());
+        let (sender, mut receiver) = tokio::sync::mpsc::channel(1);
+
+        let (_, event) = futures::join!(
+            serve_reverse(&ctx, sender),
+            receiver.recv(),
+        );
+        assert!(matches!(&event, Some(V1Event::InternalError(e))
+                if e.contains("Unable to listen")),
+            "Unexpected event: {event:?}");
+    }
+}
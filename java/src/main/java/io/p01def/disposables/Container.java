@@ -266,7 +266,8 @@ public class Container implements Closeable {
 	}
 
 	/**
-	 * Waits for an event from the container.
+	 * Waits for an event from the container. Event kinds this client
+	 * does not know about are skipped.
 	 *
 	 * @return The event that was received.
 	 * @throws IOException If an I/O error occurs.
@@ -274,13 +275,18 @@ public class Container implements Closeable {
 	 */
 	public V1Event waitForEvent() throws IOException, InterruptedException {
 		DataInputStream in = new DataInputStream(dlcConn.getInputStream());		
+		ObjectMapper mapper = new ObjectMapper();
 
-		int size = in.readInt();
-		byte[] data = new byte[size];
-		in.readFully(data);
+		while (true) {
+			int size = in.readInt();
+			byte[] data = new byte[size];
+			in.readFully(data);
 
-		ObjectMapper mapper = new ObjectMapper();
-		return mapper.readValue(data, V1Event.class);
+			V1Event event = mapper.readValue(data, V1Event.class);
+			if (!(event instanceof V1Event.Unknown)) {
+				return event;
+			}
+		}
 	}
 
 	/**
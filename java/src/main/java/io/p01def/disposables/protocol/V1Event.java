@@ -18,16 +18,19 @@
  */
 package io.p01def.disposables.protocol;
 
+import com.fasterxml.jackson.annotation.JsonIgnoreProperties;
 import com.fasterxml.jackson.annotation.JsonTypeInfo;
 import com.fasterxml.jackson.annotation.JsonSubTypes;
 
-@JsonTypeInfo(use = JsonTypeInfo.Id.NAME, property = "kind")
+@JsonTypeInfo(use = JsonTypeInfo.Id.NAME, property = "kind",
+	defaultImpl = V1Event.Unknown.class)
 @JsonSubTypes({
 	@JsonSubTypes.Type(value = V1Event.Ready.class, name = "Ready"),
 	@JsonSubTypes.Type(value = V1Event.Exited.class, name = "Exited"),
 	@JsonSubTypes.Type(value = V1Event.FailedToPrepare.class, name = "FailedToPrepare"),
 	@JsonSubTypes.Type(value = V1Event.FailedToStartEntrypoint.class, name = "FailedToStartEntrypoint"),
 	@JsonSubTypes.Type(value = V1Event.FailedTimeout.class, name = "FailedTimeout"),
+	@JsonSubTypes.Type(value = V1Event.InternalError.class, name = "InternalError"),
 })
 public class V1Event {
 	//enum
@@ -64,6 +67,21 @@ public class V1Event {
 			return "FailedTimeout";
 		}
 	}
+	public static class InternalError extends V1Event {
+		public String data;
+		@Override
+		public String toString() {
+			return "InternalError(" + data + ")";
+		}
+	}
+	//Event kinds this client does not know about
+	@JsonIgnoreProperties(ignoreUnknown = true)
+	public static class Unknown extends V1Event {
+		@Override
+		public String toString() {
+			return "Unknown";
+		}
+	}
 }
 	
 
@@ -25,8 +25,11 @@ package io.p01def.disposables.protocol;
  FailedToPrepare("Failed to prepare container") -> {"kind":"FailedToPrepare","data":"Failed to prepare container"}
  FailedToStartEntrypoint("Failed to start entrypoint") -> {"kind":"FailedToStartEntrypoint","data":"Failed to start entrypoint"}
  FailedTimeout -> {"kind":"FailedTimeout"
+ InternalError("Unable to listen") -> {"kind":"InternalError","data":"Unable to listen"}
 */
 
+import static org.junit.jupiter.api.Assertions.assertTrue;
+
 import org.junit.jupiter.api.Test;
 
 import com.fasterxml.jackson.databind.ObjectMapper;
@@ -41,6 +44,7 @@ public class V1EventTest {
 			"{\"kind\":\"FailedToPrepare\",\"data\":\"Failed to prepare container\"}",
 			"{\"kind\":\"FailedToStartEntrypoint\",\"data\":\"Failed to start entrypoint\"}",
 			"{\"kind\":\"FailedTimeout\"}",
+			"{\"kind\":\"InternalError\",\"data\":\"Unable to listen\"}",
 		};
 
 		ObjectMapper mapper = new ObjectMapper();
@@ -50,4 +54,14 @@ public class V1EventTest {
 			System.out.println(event);
 		}
 	}
+
+	@Test
+	void unknownKindIsIgnored() throws Exception {
+		String msg = "{\"kind\":\"NewKind\",\"data\":{\"port\":80}}";
+
+		ObjectMapper mapper = new ObjectMapper();
+
+		V1Event event = mapper.readValue(msg, V1Event.class);
+		assertTrue(event instanceof V1Event.Unknown);
+	}
 }
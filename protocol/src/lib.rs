@@ -23,11 +23,18 @@
  */
 pub const V1_ENV_SETUP: &str = "DISPOSABLES_V1_SETUP";
 
+/**
+ * Prefix of the line DLC writes to stderr when it fails and cannot report
+ * the failure as `V1Event::InternalError`. The rest of the line is
+ * the error message as a JSON string.
+ */
+pub const V1_INTERNAL_ERROR_PREFIX: &str = "DISPOSABLES_V1_INTERNAL_ERROR ";
+
 /**
  * Enumeration of conditions to wait for before accepting that the container
  * is ready.
  */
-#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 #[serde(tag = "kind", content = "data")]
 pub enum V1WaitCondition {
     /// Wait for a port to be connectable.
@@ -36,6 +43,207 @@ pub enum V1WaitCondition {
     Stdout(String),
     /// Wait for a command to return successfully.
     Command{argv: Vec<String>, interval_msec: u64},
+    /// Wait for the process and its descendants to listen on all
+    /// the given TCP ports, or on any TCP port when none are given.
+    /// Unlike `Port`, DLC does not connect to the ports, it looks for
+    /// the listening sockets in `/proc` instead.
+    Listening(Vec<u16>),
+    /// Wait for a Unix domain socket at the given path to be connectable.
+    UnixSocket(String),
+    /// Wait for a UDP port to answer. DLC sends `request` as a datagram
+    /// to the port and waits for a reply containing `reply`,
+    /// or for any reply when `reply` is empty.
+    Udp{port: u16, request: Vec<u8>, reply: Vec<u8>},
+    /// Wait for a TLS handshake with a port to complete. `sni` is sent
+    /// as the server name. The server's certificate is not verified,
+    /// unless `ca_file` is given, in which case it is verified against
+    /// the CA certificates in that PEM file inside the container.
+    /// The certificate is reported by `V1Event::TlsCertificates`.
+    Tls{port: u16, sni: Option<String>, ca_file: Option<String>},
+    /// Wait for a port to report `SERVING` to the standard gRPC health
+    /// checking protocol (`grpc.health.v1.Health/Check`) over plaintext
+    /// HTTP/2, for `service`, or for the whole server when `service`
+    /// is not given.
+    Grpc{port: u16, service: Option<String>},
+    /// Wait for a file, directory or socket to exist at the given path.
+    PathExists(String),
+    /// Wait for a string to be found in a line of the given file.
+    /// DLC follows the lines appended to the file, starting from
+    /// its beginning.
+    FileContains{path: String, pattern: String},
+}
+
+/**
+ * A fault that can be injected into connections passing through a proxy.
+ */
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "kind", content = "data")]
+pub enum V1Toxic {
+    /// Delay data by `latency_ms` milliseconds,
+    /// plus a random jitter of up to `jitter_ms` milliseconds.
+    Latency{latency_ms: u64, jitter_ms: u64},
+    /// Limit the rate of data transfer to `bytes_per_s` bytes per second.
+    Bandwidth{bytes_per_s: u64},
+    /// Reset the connections, including new ones.
+    ResetPeer,
+    /// Silently drop all data without closing the connections.
+    Blackhole,
+}
+
+/**
+ * Faults to inject into connections passing through a proxy.
+ */
+#[derive(Debug, Default, Clone, serde::Serialize, serde::Deserialize)]
+pub struct V1Toxics {
+    /// Toxics applied to data sent by the client to the proxied port.
+    pub upstream: Vec<V1Toxic>,
+    /// Toxics applied to data sent by the proxied port to the client.
+    pub downstream: Vec<V1Toxic>,
+}
+
+/**
+ * A named group of wait conditions. (see `V1SetupMsg::phases`)
+ */
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct V1Phase {
+    /// Name of the phase, reported by `V1Event::PhaseReached`.
+    pub name: String,
+    /// Conditions to wait for in this phase.
+    pub wait_for: Vec<V1WaitCondition>,
+}
+
+/**
+ * Certificate presented by a server in a TLS handshake.
+ * (see `V1WaitCondition::Tls`)
+ */
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct V1Certificate {
+    /// Port the certificate was presented on.
+    pub port: u16,
+    /// Distinguished name of the subject, like `CN=example.com`.
+    pub subject: String,
+    /// Expiry time, in seconds since the Unix epoch.
+    pub not_after: i64,
+    /// SHA-256 fingerprint of the DER encoded certificate, in lowercase hex.
+    pub sha256: String,
+}
+
+/**
+ * Description of a port proxied by DLC.
+ */
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct V1Proxy {
+    /// Port DLC should listen on.
+    pub listen: u16,
+    /// Port connections should be forwarded to.
+    pub target: u16,
+}
+
+/**
+ * Output stream of the container's entrypoint.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum V1OutputStream {
+    /// Standard output
+    Stdout,
+    /// Standard error
+    Stderr,
+}
+
+/**
+ * Format of the log lines written by DLC.
+ */
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum V1LogFormat {
+    /// One line of text per entry, prefixed with the stream tag.
+    #[default]
+    Plain,
+    /// One JSON object per line, with timestamps and the stream tag
+    /// as separate fields.
+    Json,
+}
+
+/**
+ * Configuration of the log lines written by DLC. DLC copies the container's
+ * output to its own stdout, along with entries describing its own progress.
+ */
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct V1LogConfig {
+    /// Format of the log lines.
+    pub format: V1LogFormat,
+    /// Whether to prefix plain log lines with wall-clock and monotonic
+    /// timestamps. JSON log lines always have timestamps.
+    pub timestamps: bool,
+    /// Tag for lines from the entrypoint's stdout.
+    pub stdout_tag: String,
+    /// Tag for lines from the entrypoint's stderr.
+    pub stderr_tag: String,
+    /// Tag for DLC's own entries.
+    pub dlc_tag: String,
+}
+
+impl Default for V1LogConfig {
+    fn default() -> Self {
+        Self {
+            format: V1LogFormat::Plain,
+            timestamps: false,
+            stdout_tag: "out".into(),
+            stderr_tag: "err".into(),
+            dlc_tag: "dlc".into(),
+        }
+    }
+}
+
+/**
+ * Size of a terminal, in characters.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct V1TermSize {
+    /// Number of rows.
+    pub rows: u16,
+    /// Number of columns.
+    pub cols: u16,
+}
+
+/**
+ * Description of a process DLC runs alongside the container's entrypoint,
+ * e.g. a helper service needed by the tests.
+ */
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct V1Sidecar {
+    /// Name of the sidecar, used in events.
+    pub name: String,
+    /// Command line of the sidecar.
+    pub argv: Vec<String>,
+    /// List of conditions to wait for before accepting that the sidecar
+    /// is ready. `Stdout` conditions are matched against the sidecar's
+    /// stdout. `Listening` and `Tls` conditions are not supported.
+    /// (see `V1Event::SidecarReady`)
+    #[serde(default)]
+    pub wait_for: Vec<V1WaitCondition>,
+    /// Tag for lines from the sidecar's stdout. The default is the name
+    /// of the sidecar followed by `.out`.
+    pub stdout_tag: Option<String>,
+    /// Tag for lines from the sidecar's stderr. The default is the name
+    /// of the sidecar followed by `.err`.
+    pub stderr_tag: Option<String>,
+}
+
+/**
+ * Policy for restarting the container's entrypoint when it exits.
+ */
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "kind", content = "data")]
+pub enum V1RestartPolicy {
+    /// Never restart the entrypoint.
+    #[default]
+    Never,
+    /// Restart the entrypoint when it exits unsuccessfully or is killed,
+    /// at most the given number of times.
+    OnFailure(u32),
+    /// Always restart the entrypoint when it exits.
+    Always,
 }
 
 /**
@@ -44,7 +252,7 @@ pub enum V1WaitCondition {
  * The setup message is serialized in JSON format and passed as an environment
  * variable to the container. (see `V1_ENV_SETUP`)
  */
-#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct V1SetupMsg {
     /// DLC should listen on this port. Disposables client will connect to 
     /// this port to receive events. When that connection is closed,
@@ -59,8 +267,122 @@ pub struct V1SetupMsg {
     /// the container is considered failed to become ready.
     pub ready_timeout_s: Option<u64>,
 
+    /// Time DLC waits for the client to connect, and for the client to
+    /// pick up requested connections. The default is 15 seconds.
+    pub client_timeout_s: Option<u64>,
+
+    /// Interval between attempts to connect to the ports in `wait_for`.
+    /// The default is 500 ms.
+    pub port_check_interval_ms: Option<u64>,
+
+    /// Timeout of a single attempt to connect to a port when checking
+    /// conditions. The default is 1000 ms.
+    pub probe_connect_timeout_ms: Option<u64>,
+
+    /// Timeout of a single run of a command when checking conditions.
+    /// Commands running longer are killed and count as failed.
+    /// When unset, commands are not timed out.
+    pub command_timeout_ms: Option<u64>,
+
+    /// Secret the client must send as `V1Request::Auth` at the beginning of
+    /// every connection to the DLC port. When unset, connections are not
+    /// authenticated.
+    pub token: Option<String>,
+
+    /// Time DLC keeps the container running after the control connection
+    /// is closed, waiting for a client to reattach using `V1Request::Attach`.
+    /// When unset, DLC exits as soon as the control connection is closed.
+    pub linger_s: Option<u64>,
+
+    /// Maximum time the container runs, in seconds. When it is reached,
+    /// DLC sends `V1Event::LifetimeExpired`, stops the entrypoint with
+    /// SIGTERM, or SIGKILL after 10 seconds, and exits, whether or not
+    /// a client is connected. When unset, the lifetime is not limited.
+    pub max_lifetime_s: Option<u64>,
+
     /// List of files to be written before starting the container's entrypoint.
     pub files: Vec<(String, String)>,
+
+    /// List of strings that indicate that the container failed to start.
+    /// When one of them is found in the container's stdout or stderr before 
+    /// the container is ready, DLC sends `V1Event::FailedOutputMatch`.
+    #[serde(default)]
+    pub fail_on: Vec<String>,
+
+    /// Whether DLC should send the container's output to the client
+    /// as `V1Event::Output` events.
+    #[serde(default)]
+    pub forward_output: bool,
+
+    /// Whether the entrypoint's stdin should be a pipe, fed using
+    /// `V1Request::Stdin`. Otherwise stdin is inherited from DLC.
+    #[serde(default)]
+    pub stdin: bool,
+
+    /// Policy for restarting the entrypoint when it exits. Each restart
+    /// is reported as `V1Event::Restarting`, and the readiness of the new run
+    /// is evaluated from the beginning. Only the first run reads the data
+    /// fed using `V1Request::Stdin`.
+    #[serde(default)]
+    pub restart: V1RestartPolicy,
+
+    /// Delay before the first restart of the entrypoint. The delay doubles
+    /// after each restart, up to 30 seconds. The default is 1000 ms.
+    pub restart_backoff_ms: Option<u64>,
+
+    /// Phases the entrypoint goes through before it is ready, in order.
+    /// The conditions of all phases are checked along with `wait_for`.
+    /// `V1Event::PhaseReached` is sent once the conditions of a phase and
+    /// of all the phases before it are satisfied.
+    #[serde(default)]
+    pub phases: Vec<V1Phase>,
+
+    /// List of processes DLC runs alongside the entrypoint, after writing
+    /// `files`. Sidecars are not restarted, and are killed when DLC exits.
+    #[serde(default)]
+    pub sidecars: Vec<V1Sidecar>,
+
+    /// When set, the entrypoint is run on a pseudo-terminal of the given
+    /// size. Its stdout and stderr are then merged, and reported as stdout.
+    /// When `stdin` is also set, `V1Request::Stdin` writes to the terminal.
+    pub pty: Option<V1TermSize>,
+
+    /// Configuration of the log lines written by DLC.
+    #[serde(default)]
+    pub log: V1LogConfig,
+
+    /// Maximum length of a line of output in bytes. Longer lines are split
+    /// into several lines. The default is 65536.
+    pub max_line_length: Option<usize>,
+
+    /// List of ports DLC should listen on inside the container.
+    /// Connections accepted on these ports are forwarded to the client.
+    /// (see `V1Event::TunnelRequested`)
+    #[serde(default)]
+    pub reverse_tunnels: Vec<u16>,
+
+    /// List of ports DLC should proxy. Faults can be injected into
+    /// the proxied connections using `V1Request::SetToxics`.
+    #[serde(default)]
+    pub proxies: Vec<V1Proxy>,
+
+    /// List of conditions DLC keeps checking after the container is ready.
+    /// Only `Port`, `Udp`, `Tls`, `Grpc`, `UnixSocket` and `Command`
+    /// conditions are supported, commands are run once per check.
+    #[serde(default)]
+    pub liveness: Vec<V1WaitCondition>,
+
+    /// Interval between liveness checks.
+    pub liveness_interval_ms: Option<u64>,
+
+    /// Number of consecutive failed liveness checks after which
+    /// the container is considered unhealthy.
+    pub liveness_failure_threshold: Option<u32>,
+
+    /// Number of consecutive failed liveness checks after which
+    /// the container's entrypoint is killed. When unset, the entrypoint
+    /// is never killed.
+    pub liveness_kill_threshold: Option<u32>,
 }
 
 /**
@@ -73,14 +395,128 @@ pub struct V1SetupMsg {
 pub enum V1Event {
     /// The container is ready to use.
     Ready,
+    /// TCP ports the entrypoint and its descendants listen on.
+    /// Sent just before `V1Event::Ready` when the entrypoint has
+    /// a `V1WaitCondition::Listening` condition.
+    Listening(Vec<u16>),
+    /// Certificates presented by the ports of `V1WaitCondition::Tls`
+    /// conditions. Sent just before `V1Event::Ready`.
+    TlsCertificates(Vec<V1Certificate>),
     /// The container's entrypoint has exited.
     Exited(Option<i32>),
+    /// The container's entrypoint has exited with the given code, and is
+    /// going to be restarted. (see `V1SetupMsg::restart`) `count` is
+    /// the number of restarts so far, including this one.
+    Restarting{code: Option<i32>, count: u32},
     /// Failed to prepare the container.
     FailedToPrepare(String),
     /// Failed to start the container's entrypoint.
     FailedToStartEntrypoint(String),
     /// Timeout occured while waiting for the container to become ready.
     FailedTimeout,
+    /// A line matching one of the failure patterns was found in the
+    /// container's output before the container became ready.
+    FailedOutputMatch{pattern: String, line: String},
+    /// A line of output from the container's entrypoint.
+    /// Only sent when `V1SetupMsg::forward_output` is set.
+    Output{stream: V1OutputStream, line: String},
+    /// Reading the container's output failed. The stream is not read
+    /// any further.
+    OutputError{stream: V1OutputStream, error: String},
+    /// A connection was accepted on a reverse tunnel port. The client should
+    /// pick it up by opening a new connection with `V1Request::Accept(id)`.
+    TunnelRequested{port: u16, id: u64},
+    /// A liveness check failed after the container became ready.
+    Unhealthy(String),
+    /// Liveness checks succeeded again after the container became unhealthy.
+    Healthy,
+    /// All wait conditions of the sidecar with the given name are satisfied.
+    SidecarReady(String),
+    /// Timeout occured while waiting for the sidecar with the given name
+    /// to become ready.
+    SidecarFailedTimeout(String),
+    /// Failed to start the sidecar with the given name.
+    SidecarFailedToStart{name: String, error: String},
+    /// The sidecar with the given name has exited.
+    SidecarExited{name: String, code: Option<i32>},
+    /// A phase has been reached, i.e. its conditions and those of the phases
+    /// before it are satisfied. (see `V1SetupMsg::phases`) Phases are
+    /// reported in order, before `V1Event::Ready`.
+    PhaseReached(String),
+    /// The maximum lifetime of the container has been reached.
+    /// (see `V1SetupMsg::max_lifetime_s`) DLC stops the entrypoint and exits.
+    LifetimeExpired,
+    /// DLC itself failed, e.g. when the setup message cannot be parsed or
+    /// a port cannot be listened on.
+    InternalError(String),
 }
 
+/**
+ * Enumeration of requests that the client can send to DLC.
+ *
+ * DLC sends events on the control connection. Every other connection to
+ * the DLC port must begin with a request, to which DLC replies with
+ * a `V1Reply`.
+ *
+ * When `V1SetupMsg::token` is set, every connection must begin with
+ * `V1Request::Auth` carrying the token, and DLC closes connections that do
+ * not, without replying. The control connection is then the one making
+ * `V1Request::Attach`. Otherwise the first connection accepted by DLC is
+ * the control connection.
+ *
+ * Like events, requests are serialized in JSON format and prefixed with
+ * their length.
+ */
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "kind", content = "data")]
+pub enum V1Request {
+    /// Authenticate the connection with the token from the setup message.
+    /// DLC does not reply to this request.
+    Auth(String),
+    /// Connect to the given TCP port inside the container. Once DLC replies
+    /// with `V1Reply::Ok`, the rest of the connection carries the data
+    /// of the tunnelled stream.
+    Connect(u16),
+    /// Connect to the Unix domain socket at the given path inside
+    /// the container. Once DLC replies with `V1Reply::Ok`, the rest of
+    /// the connection carries the data of the tunnelled stream.
+    ConnectUnix(String),
+    /// Pick up the reverse tunnelled connection with the given ID.
+    /// Once DLC replies with `V1Reply::Ok`, the rest of the connection carries
+    /// the data of the tunnelled stream.
+    Accept(u64),
+    /// Make this connection the control connection. Once DLC replies with
+    /// `V1Reply::Ok`, events are sent on this connection, starting with
+    /// the ones that could not be delivered on the previous one.
+    /// The previous control connection, if any, is closed.
+    Attach,
+    /// Feed the entrypoint's stdin. Once DLC replies with `V1Reply::Ok`,
+    /// the data sent on the connection is written to the entrypoint's stdin.
+    /// When the client stops sending, DLC closes the entrypoint's stdin, or
+    /// sends end-of-file on a pseudo-terminal, ending an incomplete last
+    /// line with a newline first, and then closes the connection.
+    /// Only allowed once, when `V1SetupMsg::stdin` is set.
+    Stdin,
+    /// Change the size of the entrypoint's pseudo-terminal.
+    /// (see `V1SetupMsg::pty`) DLC replies with `V1Reply::Ok`.
+    Resize(V1TermSize),
+    /// Keep the container running for the given number of seconds after
+    /// the control connection is closed, e.g. to debug a failed test.
+    /// DLC replies with `V1Reply::Ok`.
+    Keep(u64),
+    /// Replace the toxics of the proxy for the given target port.
+    /// DLC replies with `V1Reply::Ok` once the toxics are in effect.
+    SetToxics{port: u16, toxics: V1Toxics},
+}
 
+/**
+ * Reply sent by DLC to a request.
+ */
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "kind", content = "data")]
+pub enum V1Reply {
+    /// The request was successful.
+    Ok,
+    /// The request failed.
+    Error(String),
+}
@@ -21,27 +21,69 @@
  */
 
 
-use std::collections::HashMap;
-use std::io::Read;
-use std::net::TcpStream;
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
 use std::process::{Child, ChildStdout, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
 use base64::Engine;
-use disposables_protocol::{V1_ENV_SETUP, V1Event, V1SetupMsg, V1WaitCondition};
+use disposables_protocol::{V1_ENV_SETUP, V1_INTERNAL_ERROR_PREFIX};
+use disposables_protocol::{V1Event, V1Reply, V1Request};
+use disposables_protocol::{V1OutputStream, V1Phase, V1Proxy, V1SetupMsg};
+use disposables_protocol::V1Sidecar;
+use disposables_protocol::V1Toxics;
+use disposables_protocol::{V1LogConfig, V1RestartPolicy, V1TermSize};
+use disposables_protocol::{V1Certificate, V1WaitCondition};
 
 use crate::args::Args;
 use crate::context::{DLC_MOUNT_POINT, ExecError, Context};
 use crate::util::try_use;
 
 const DLC_PORT: u16 = 4;
+//Default of V1SetupMsg::liveness_failure_threshold, as applied by DLC
+const DEFAULT_LIVENESS_FAILURE_THRESHOLD: u32 = 3;
+//Time to keep the container for after `Container::keep()`, unless set
+//by `ContainerParams::keep_on_failure()`
+const DEFAULT_KEEP_DURATION: Duration = Duration::from_secs(600);
+
+//Transport protocol of a published port
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Protocol {
+    Tcp,
+    Udp,
+}
+
+impl Protocol {
+    fn as_str(self) -> &'static str {
+        match self {
+            Protocol::Tcp => "tcp",
+            Protocol::Udp => "udp",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Protocol> {
+        match s {
+            "tcp" => Some(Protocol::Tcp),
+            "udp" => Some(Protocol::Udp),
+            _ => None,
+        }
+    }
+}
 
 /**
  * A type for storing and manipulating parameters needed to build a container.
  */
 pub struct ContainerParams {
     image: String,
-    ports: Vec<u16>,
+    ports: Vec<(u16, Protocol)>,
     setup_msg: V1SetupMsg,
+    reverse_tunnels: HashMap<u16, String>,
+    keep_on_failure: Option<Duration>,
 
     entrypoint: Option<Args>,
     cmd: Option<Args>, 
@@ -61,8 +103,33 @@ impl ContainerParams {
                 port: DLC_PORT,
                 wait_for: Vec::new(),
                 ready_timeout_s: None,
+                client_timeout_s: None,
+                port_check_interval_ms: None,
+                probe_connect_timeout_ms: None,
+                command_timeout_ms: None,
+                token: None,
+                linger_s: None,
+                max_lifetime_s: None,
                 files: Vec::new(),
+                fail_on: Vec::new(),
+                forward_output: false,
+                stdin: false,
+                phases: Vec::new(),
+                sidecars: Vec::new(),
+                pty: None,
+                restart: V1RestartPolicy::Never,
+                restart_backoff_ms: None,
+                log: V1LogConfig::default(),
+                max_line_length: None,
+                reverse_tunnels: Vec::new(),
+                proxies: Vec::new(),
+                liveness: Vec::new(),
+                liveness_interval_ms: None,
+                liveness_failure_threshold: None,
+                liveness_kill_threshold: None,
             },
+            reverse_tunnels: HashMap::new(),
+            keep_on_failure: None,
 
             entrypoint: None,
             cmd: None,
@@ -72,9 +139,55 @@ impl ContainerParams {
 
     /**
      * Adds a port to be forwarded from the container to the host.
+     *
+     * Ports that are only needed by the test process can instead be reached
+     * through DLC using `Container::connect()`, without forwarding them.
      */
     pub fn port(&mut self, port: u16) -> &mut Self {
-        self.ports.push(port);
+        self.ports.push((port, Protocol::Tcp));
+        self
+    }
+
+    /**
+     * Adds a UDP port to be forwarded from the container to the host.
+     * The mapping is returned by `Container::udp_port()`.
+     */
+    pub fn udp_port(&mut self, port: u16) -> &mut Self {
+        self.ports.push((port, Protocol::Udp));
+        self
+    }
+
+    /**
+     * Adds a port to be forwarded from the container to the host through
+     * a proxy run by DLC.
+     *
+     * Faults like latency, bandwidth limits, connection resets and blackholing
+     * can be injected into the proxied connections at runtime using
+     * `Container::set_toxics()`. `Container::port()` returns the address of
+     * the proxy for this port.
+     *
+     * The proxies listen on the ports following DLC's own port (`5`, `6`,
+     * and so on), so these ports cannot be used by the container.
+     */
+    pub fn proxy_port(&mut self, port: u16) -> &mut Self {
+        let listen = DLC_PORT + 1 + self.setup_msg.proxies.len() as u16;
+        self.setup_msg.proxies.push(V1Proxy { listen, target: port });
+        self
+    }
+
+    /**
+     * Makes a service on the host reachable from inside the container.
+     *
+     * DLC listens on `port` inside the container and forwards each connection
+     * it accepts back through the DLC connection to `host_addr`, which is
+     * connected to from the test process. This way the container does not
+     * need to be able to reach the host's network.
+     */
+    pub fn reverse_tunnel(&mut self, port: u16, host_addr: impl Into<String>)
+    -> &mut Self {
+        if self.reverse_tunnels.insert(port, host_addr.into()).is_none() {
+            self.setup_msg.reverse_tunnels.push(port);
+        }
         self
     }
 
@@ -93,12 +206,50 @@ impl ContainerParams {
 
     /**
      * Add a condition to wait for before accepting that the container is ready.
+     *
+     * After `phase()` has been called, the condition belongs to the last
+     * phase started.
      */
     pub fn wait_for(&mut self, condition: V1WaitCondition) -> &mut Self {
-        self.setup_msg.wait_for.push(condition);
+        match self.setup_msg.phases.last_mut() {
+            Some(phase) => phase.wait_for.push(condition),
+            None => self.setup_msg.wait_for.push(condition),
+        }
         self 
     }
 
+    /**
+     * Starts a readiness phase named `name`. The conditions added after this
+     * call, using `wait_for()` and the `wait_for_*()` methods, belong to the
+     * phase, until the next phase is started.
+     *
+     * DLC sends `V1Event::PhaseReached` once the conditions of a phase and
+     * of all the phases before it are satisfied, and `V1Event::Ready` after
+     * the last phase. The conditions of all phases are checked from
+     * the start. (see `Container::wait_for_phase()`)
+     *
+     * ```rust
+     * # use disposables::ContainerParams;
+     *
+     * let mut container = ContainerParams::new("docker.io/postgres:alpine")
+     *     .env("POSTGRES_PASSWORD", "postgres")
+     *     .phase("initialized")
+     *     .wait_for_stdout("PostgreSQL init process complete")
+     *     .phase("accepting connections")
+     *     .wait_for_cmd(["pg_isready", "-h", "127.0.0.1"], 500)
+     *     .create().unwrap();
+     *
+     * container.wait_for_phase("initialized").unwrap();
+     * ```
+     */
+    pub fn phase(&mut self, name: impl Into<String>) -> &mut Self {
+        self.setup_msg.phases.push(V1Phase {
+            name: name.into(),
+            wait_for: Vec::new(),
+        });
+        self
+    }
+
     /**
      * Add a condition to wait for a port to be connectable.
      * When the port is connectable, the container is considered ready.
@@ -109,6 +260,112 @@ impl ContainerParams {
         self.wait_for(V1WaitCondition::Port(port))
     }
 
+    /**
+     * Add a condition to wait for the entrypoint or its descendants to listen
+     * on all the given TCP ports, or on any TCP port if `ports` is empty.
+     * Unlike `wait_for_port()`, DLC does not connect to the ports, so that
+     * the server does not log failed connections. The ports found are
+     * returned by `Container::listening_ports()`.
+     */
+    pub fn wait_for_listening(&mut self, ports: impl Into<Vec<u16>>)
+    -> &mut Self {
+        self.wait_for(V1WaitCondition::Listening(ports.into()))
+    }
+
+    /**
+     * Add a condition to wait for the UDP port `port` to answer.
+     *
+     * DLC sends `request` as a datagram to the port, and waits for a reply
+     * that contains `reply`, or for any reply if `reply` is empty.
+     */
+    pub fn wait_for_udp(&mut self, port: u16, request: impl Into<Vec<u8>>,
+        reply: impl Into<Vec<u8>>) -> &mut Self {
+        self.wait_for(V1WaitCondition::Udp {
+            port,
+            request: request.into(),
+            reply: reply.into(),
+        })
+    }
+
+    /**
+     * Add a condition to wait for a TLS handshake with `port` to complete,
+     * without verifying the server's certificate.
+     *
+     * The certificate presented by the port is returned by
+     * `Container::tls_certificates()`.
+     */
+    pub fn wait_for_tls(&mut self, port: u16) -> &mut Self {
+        self.wait_for_tls_with(port, None, None)
+    }
+
+    /**
+     * Add a condition to wait for a TLS handshake with `port` to complete,
+     * sending `sni` as the server name. If `ca_file` is given, the server's
+     * certificate is verified against the CA certificates in that PEM file
+     * inside the container.
+     */
+    pub fn wait_for_tls_with(&mut self, port: u16, sni: Option<&str>,
+        ca_file: Option<&str>) -> &mut Self {
+        self.wait_for(V1WaitCondition::Tls {
+            port,
+            sni: sni.map(str::to_owned),
+            ca_file: ca_file.map(str::to_owned),
+        })
+    }
+
+    /**
+     * Add a condition to wait for `port` to report that the server is
+     * serving, using the standard gRPC health checking protocol.
+     *
+     * DLC speaks the protocol itself, so `grpc_health_probe` is not needed
+     * in the image. TLS is not supported.
+     */
+    pub fn wait_for_grpc(&mut self, port: u16) -> &mut Self {
+        self.wait_for(V1WaitCondition::Grpc { port, service: None })
+    }
+
+    /**
+     * Add a condition to wait for `port` to report that `service` is
+     * serving, using the standard gRPC health checking protocol.
+     */
+    pub fn wait_for_grpc_service(&mut self, port: u16,
+        service: impl Into<String>) -> &mut Self {
+        self.wait_for(V1WaitCondition::Grpc {
+            port,
+            service: Some(service.into()),
+        })
+    }
+
+    /**
+     * Add a condition to wait for the Unix domain socket at `path` inside
+     * the container to be connectable.
+     */
+    pub fn wait_for_unix_socket(&mut self, path: impl Into<String>)
+    -> &mut Self {
+        self.wait_for(V1WaitCondition::UnixSocket(path.into()))
+    }
+
+    /**
+     * Add a condition to wait for a file, directory or socket to exist
+     * at `path` inside the container, e.g. a pid file.
+     */
+    pub fn wait_for_path(&mut self, path: impl Into<String>) -> &mut Self {
+        self.wait_for(V1WaitCondition::PathExists(path.into()))
+    }
+
+    /**
+     * Add a condition to wait for a pattern to be found in a line of the file
+     * at `path` inside the container, e.g. a log file. The file is read from
+     * its beginning.
+     */
+    pub fn wait_for_file(&mut self, path: impl Into<String>,
+        expr: impl Into<String>) -> &mut Self {
+        self.wait_for(V1WaitCondition::FileContains {
+            path: path.into(),
+            pattern: expr.into(),
+        })
+    }
+
     /**
      * Add a condition to wait for a pattern to be found in the container's 
      * stdout. When the pattern is found, the container is considered ready.
@@ -117,6 +374,37 @@ impl ContainerParams {
         self.wait_for(V1WaitCondition::Stdout(expr.into()))
     }
 
+    /**
+     * Sets how DLC formats the container's log, as seen in `podman logs`.
+     * Entrypoint output and DLC's own entries can be written as plain lines
+     * or as JSON lines, optionally with wall-clock and monotonic timestamps.
+     */
+    pub fn log_config(&mut self, config: V1LogConfig) -> &mut Self {
+        self.setup_msg.log = config;
+        self
+    }
+
+    /**
+     * Sets the maximum length of a line of the container's output in bytes.
+     * Longer lines are split into several lines before they are matched
+     * against patterns. The default is 65536.
+     */
+    pub fn max_line_length(&mut self, bytes: usize) -> &mut Self {
+        self.setup_msg.max_line_length = Some(bytes);
+        self
+    }
+
+    /**
+     * Add a pattern that indicates that the container failed to start.
+     * When the pattern is found in the container's stdout or stderr before
+     * the container is ready, `V1Event::FailedOutputMatch` is sent
+     * immediately instead of waiting for the ready timeout.
+     */
+    pub fn fail_on_output(&mut self, expr: impl Into<String>) -> &mut Self {
+        self.setup_msg.fail_on.push(expr.into());
+        self
+    }
+
     /**
      * Run a command in the container to check if it is ready.
      * When the command returns successfully, the container is considered ready.
@@ -135,6 +423,169 @@ impl ContainerParams {
         })
     }
 
+    /**
+     * Sets how long DLC waits for the client to connect after the container
+     * is started. The same timeout applies to reverse tunnelled connections
+     * waiting to be picked up. The default is 15 seconds.
+     */
+    pub fn client_timeout(&mut self, timeout_s: u64) -> &mut Self {
+        self.setup_msg.client_timeout_s = Some(timeout_s);
+        self
+    }
+
+    /**
+     * Sets the interval between attempts to connect to the ports
+     * the container waits for. The default is 500 ms.
+     */
+    pub fn port_check_interval(&mut self, interval_msec: u64) -> &mut Self {
+        self.setup_msg.port_check_interval_ms = Some(interval_msec);
+        self
+    }
+
+    /**
+     * Sets the timeout of a single attempt to connect to a port when
+     * checking readiness or liveness. The default is 1000 ms.
+     */
+    pub fn probe_connect_timeout(&mut self, timeout_msec: u64) -> &mut Self {
+        self.setup_msg.probe_connect_timeout_ms = Some(timeout_msec);
+        self
+    }
+
+    /**
+     * Sets the timeout of a single run of a command when checking readiness
+     * or liveness. Commands running longer are killed and considered failed.
+     * By default commands are not timed out.
+     */
+    pub fn command_timeout(&mut self, timeout_msec: u64) -> &mut Self {
+        self.setup_msg.command_timeout_ms = Some(timeout_msec);
+        self
+    }
+
+    /**
+     * Keeps the container running for `linger_s` seconds after
+     * the `Container` is dropped or its connection to DLC is lost, so that
+     * `Container::attach()` can take over. Without it, the container is
+     * terminated as soon as that happens.
+     */
+    pub fn linger(&mut self, linger_s: u64) -> &mut Self {
+        self.setup_msg.linger_s = Some(linger_s);
+        self
+    }
+
+    /**
+     * Stops the container after `max_lifetime_s` seconds, even if
+     * the client is still connected, e.g. when a test hangs.
+     * DLC sends `V1Event::LifetimeExpired` and stops the entrypoint.
+     */
+    pub fn max_lifetime(&mut self, max_lifetime_s: u64) -> &mut Self {
+        self.setup_msg.max_lifetime_s = Some(max_lifetime_s);
+        self
+    }
+
+    /**
+     * Makes the entrypoint's stdin a pipe, which can be written to
+     * using `Container::stdin()`. Otherwise the entrypoint's stdin is empty.
+     */
+    pub fn pipe_stdin(&mut self) -> &mut Self {
+        self.setup_msg.stdin = true;
+        self
+    }
+
+    /**
+     * Runs a process alongside the entrypoint, e.g. a helper service that is
+     * not part of the image. Its readiness and exit are reported
+     * as `V1Event::SidecarReady` and `V1Event::SidecarExited`.
+     */
+    pub fn sidecar(&mut self, sidecar: V1Sidecar) -> &mut Self {
+        self.setup_msg.sidecars.push(sidecar);
+        self
+    }
+
+    /**
+     * Runs the entrypoint on a pseudo-terminal with the given size,
+     * for programs that behave differently when not on a terminal.
+     * Stdout and stderr of the entrypoint are merged, and are reported
+     * as stdout. Stdin, if piped, is fed through the terminal.
+     * (see `Container::resize()`)
+     */
+    pub fn pty(&mut self, rows: u16, cols: u16) -> &mut Self {
+        self.setup_msg.pty = Some(V1TermSize { rows, cols });
+        self
+    }
+
+    /**
+     * Sets when the entrypoint is restarted after it exits. Each restart
+     * is reported as `V1Event::Restarting`, after which the container's
+     * readiness is evaluated again.
+     */
+    pub fn restart(&mut self, policy: V1RestartPolicy) -> &mut Self {
+        self.setup_msg.restart = policy;
+        self
+    }
+
+    /**
+     * Sets the delay before the first restart of the entrypoint, which
+     * doubles after each restart. The default is 1000 ms.
+     */
+    pub fn restart_backoff(&mut self, backoff_msec: u64) -> &mut Self {
+        self.setup_msg.restart_backoff_ms = Some(backoff_msec);
+        self
+    }
+
+    /**
+     * Keeps the container running for `duration` when the `Container` is
+     * dropped during a panic, e.g. when a test fails, so that it can be
+     * inspected. (see `Container::keep()`)
+     *
+     * The default is fetched from `DISPOSABLES_KEEP_ON_FAILURE` environment
+     * variable, which holds the number of seconds to keep the container.
+     */
+    pub fn keep_on_failure(&mut self, duration: Duration) -> &mut Self {
+        self.keep_on_failure = Some(duration);
+        self
+    }
+
+    /**
+     * Add a condition that is checked periodically after the container
+     * becomes ready. When the check fails repeatedly, the container is
+     * reported as unhealthy. (see `Container::health()`)
+     *
+     * Only port, UDP, TLS, gRPC, Unix socket and command conditions are
+     * supported. Commands are run once per check, their `interval_msec`
+     * is ignored.
+     */
+    pub fn liveness(&mut self, condition: V1WaitCondition) -> &mut Self {
+        self.setup_msg.liveness.push(condition);
+        self
+    }
+
+    /**
+     * Sets the interval between liveness checks. The default is 1000 ms.
+     */
+    pub fn liveness_interval(&mut self, interval_msec: u64) -> &mut Self {
+        self.setup_msg.liveness_interval_ms = Some(interval_msec);
+        self
+    }
+
+    /**
+     * Sets the number of consecutive failed liveness checks after which 
+     * the container is reported as unhealthy. The default is 3.
+     */
+    pub fn liveness_failure_threshold(&mut self, failures: u32) -> &mut Self {
+        self.setup_msg.liveness_failure_threshold = Some(failures);
+        self
+    }
+
+    /**
+     * Kills the container's entrypoint after the given number of consecutive
+     * failed liveness checks. By default the entrypoint is never killed.
+     * `failures` must not be smaller than the liveness failure threshold.
+     */
+    pub fn kill_when_unhealthy(&mut self, failures: u32) -> &mut Self {
+        self.setup_msg.liveness_kill_threshold = Some(failures);
+        self
+    }
+
     /**
      * Replaces the container's entrypoint with the given argument list.
      */
@@ -172,8 +623,15 @@ impl ContainerParams {
 pub struct Container {
     ctx: Context,
     id: String, 
-    port_map: HashMap<u16, String>,
+    port_map: HashMap<(u16, Protocol), String>,
+    dlc: DlcEndpoint,
     dlc_conn: TcpStream,
+    events: Mutex<Receiver<Result<V1Event, ReadError>>>,
+    unhealthy: Arc<Mutex<Option<String>>>,
+    listening: Arc<Mutex<Vec<u16>>>,
+    certificates: Arc<Mutex<Vec<V1Certificate>>>,
+    keep_on_failure: Option<Duration>,
+    keep: bool,
 }
 
 ///Error while reading from the DLC port.
@@ -200,9 +658,269 @@ where for<'a> T: serde::Deserialize<'a>
     serde_json::from_slice(&pdu_body).map_err(ReadError::Deserialize)
 }
 
+fn write_pdu<T>(stream: &mut impl Write, value: &T) -> Result<(), std::io::Error>
+where T: serde::Serialize
+{
+    let pdu_body = serde_json::to_vec(value).expect("Error serializing PDU");
+    let mut buf = Vec::with_capacity(pdu_body.len() + 4);
+    buf.extend_from_slice(&(pdu_body.len() as u32).to_be_bytes());
+    buf.extend_from_slice(&pdu_body);
+    stream.write_all(&buf)
+}
+
+//Address of the DLC port, along with the token DLC expects as the first
+//frame of every connection.
+#[derive(Clone)]
+struct DlcEndpoint {
+    addr: String,
+    token: String,
+}
+
+fn new_token() -> String {
+    rand::random::<[u8; 32]>().iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+fn connect_dlc(dlc: &DlcEndpoint) -> Result<TcpStream, Error> {
+    let mut conn = try_use(dlc.addr.split_whitespace(), |x| {
+        TcpStream::connect(x).map_err(|e| (x.to_owned(), e))
+    }).map_err(Error::CannotConnectToDlc)?;
+    write_pdu(&mut conn, &V1Request::Auth(dlc.token.clone()))
+        .map_err(Error::CannotWritePDU)?;
+    Ok(conn)
+}
+
+//TCP ports a wait condition connects to.
+fn condition_ports(condition: &V1WaitCondition) -> Vec<u16> {
+    match condition {
+        V1WaitCondition::Port(port)
+        | V1WaitCondition::Tls{port, ..}
+        | V1WaitCondition::Grpc{port, ..} => vec![*port],
+        V1WaitCondition::Listening(ports) => ports.clone(),
+        _ => Vec::new(),
+    }
+}
+
+fn validate(setup_msg: &V1SetupMsg, ports: &[(u16, Protocol)])
+-> Result<(), Error> {
+    for (name, value) in [
+        ("client_timeout", setup_msg.client_timeout_s),
+        ("port_check_interval", setup_msg.port_check_interval_ms),
+        ("probe_connect_timeout", setup_msg.probe_connect_timeout_ms),
+        ("command_timeout", setup_msg.command_timeout_ms),
+        ("liveness_interval", setup_msg.liveness_interval_ms),
+        ("liveness_failure_threshold",
+            setup_msg.liveness_failure_threshold.map(u64::from)),
+        ("restart_backoff", setup_msg.restart_backoff_ms),
+        ("max_lifetime", setup_msg.max_lifetime_s),
+    ] {
+        if value == Some(0) {
+            return Err(Error::InvalidParams(
+                format!("{name} must be greater than zero")));
+        }
+    }
+    let failures = setup_msg.liveness_failure_threshold
+        .unwrap_or(DEFAULT_LIVENESS_FAILURE_THRESHOLD);
+    if setup_msg.liveness_kill_threshold.is_some_and(|kill| kill < failures) {
+        return Err(Error::InvalidParams(format!("kill_when_unhealthy must \
+            not be smaller than the liveness failure threshold ({failures})")));
+    }
+    let mut names = HashSet::new();
+    if let Some(phase) = setup_msg.phases.iter()
+        .find(|phase| !names.insert(&phase.name)) {
+        return Err(Error::InvalidParams(
+            format!("phase {:?} is defined more than once", phase.name)));
+    }
+    //Their results are only reported for the entrypoint
+    if let Some(sidecar) = setup_msg.sidecars.iter()
+        .find(|sidecar| sidecar.wait_for.iter().any(|condition| matches!(
+            condition, V1WaitCondition::Listening(_)
+                | V1WaitCondition::Tls{..}))) {
+        return Err(Error::InvalidParams(format!("sidecar {:?} cannot wait \
+            for listening ports or TLS handshakes", sidecar.name)));
+    }
+    //DLC listens on the listen ports of the proxies, so these cannot be
+    //used by the container.
+    let reserved: HashSet<u16> = setup_msg.proxies.iter()
+        .map(|proxy| proxy.listen)
+        .collect();
+    let used = setup_msg.proxies.iter().map(|proxy| proxy.target)
+        .chain(setup_msg.reverse_tunnels.iter().copied())
+        .chain(ports.iter().filter(|(_, protocol)| *protocol == Protocol::Tcp)
+            .map(|(port, _)| *port))
+        .chain(setup_msg.wait_for.iter().chain(&setup_msg.liveness)
+            .chain(setup_msg.phases.iter().flat_map(|phase| &phase.wait_for))
+            .chain(setup_msg.sidecars.iter()
+                .flat_map(|sidecar| &sidecar.wait_for))
+            .flat_map(condition_ports));
+    for port in used {
+        if reserved.contains(&port) {
+            return Err(Error::InvalidParams(
+                format!("port {port} is used by a proxy")));
+        }
+    }
+    Ok(())
+}
+
+//Looks for a failure DLC could only report in the container's logs.
+fn find_internal_error(ctx: &Context, id: &str) -> Option<String> {
+    let output = Command::new(ctx.engine())
+        .args(["logs", id])
+        .output().ok()?;
+    String::from_utf8_lossy(&output.stderr).lines()
+        .find_map(|line| line.strip_prefix(V1_INTERNAL_ERROR_PREFIX))
+        .map(|message| serde_json::from_str(message)
+            .unwrap_or_else(|_| message.to_owned()))
+}
+
+//Time to keep the container after a failure, as set in the environment.
+fn keep_on_failure_from_env() -> Option<Duration> {
+    let value = std::env::var("DISPOSABLES_KEEP_ON_FAILURE").ok()?;
+    match value.parse() {
+        Ok(keep_s) => Some(Duration::from_secs(keep_s)),
+        Err(e) => {
+            log::warn!("Ignoring DISPOSABLES_KEEP_ON_FAILURE={value}: {e}");
+            None
+        },
+    }
+}
+
+//Proxied ports are published on their listen port, but are looked up
+//by their target port.
+fn remap_proxies(port_map: &mut HashMap<(u16, Protocol), String>,
+    proxies: &[V1Proxy]) {
+    for proxy in proxies {
+        if let Some(output) = port_map.remove(&(proxy.listen, Protocol::Tcp)) {
+            port_map.insert((proxy.target, Protocol::Tcp), output);
+        }
+    }
+}
+
+//Parses the output of `podman port <id>`, which has lines like
+//`4/tcp -> 0.0.0.0:40123`, into the same form as `podman port <id> <port>`.
+fn parse_port_list(output: &str)
+-> Result<HashMap<(u16, Protocol), String>, Error> {
+    let mut port_map = HashMap::<(u16, Protocol), String>::new();
+    for line in output.lines().filter(|l| !l.trim().is_empty()) {
+        let (port, addr) = line.split_once(" -> ")
+            .and_then(|(port, addr)| {
+                let (port, protocol) = port.trim().split_once('/')?;
+                Some(((port.parse::<u16>().ok()?, Protocol::parse(protocol)?),
+                    addr.trim()))
+            })
+            .ok_or_else(|| Error::CannotParseMappedPort(line.to_owned()))?;
+        let entry = port_map.entry(port).or_default();
+        if !entry.is_empty() {
+            entry.push('\n');
+        }
+        entry.push_str(addr);
+    }
+    Ok(port_map)
+}
+
+fn splice(a: TcpStream, b: TcpStream) -> Result<(), std::io::Error> {
+    let mut a_read = a.try_clone()?;
+    let mut b_write = b.try_clone()?;
+    let thread = std::thread::spawn(move || {
+        let _ = std::io::copy(&mut a_read, &mut b_write);
+        let _ = b_write.shutdown(Shutdown::Write);
+    });
+
+    let (mut b_read, mut a_write) = (b, a);
+    let _ = std::io::copy(&mut b_read, &mut a_write);
+    let _ = a_write.shutdown(Shutdown::Write);
+
+    let _ = thread.join();
+    Ok(())
+}
+
+//Opens a new connection to DLC and makes the given request on it.
+fn request_dlc(dlc: &DlcEndpoint, request: &V1Request)
+-> Result<TcpStream, Error> {
+    let mut conn = connect_dlc(dlc)?;
+    write_pdu(&mut conn, request).map_err(Error::CannotWritePDU)?;
+    match read_reply(&mut conn)? {
+        V1Reply::Ok => Ok(conn),
+        V1Reply::Error(e) => Err(Error::RequestRefused(e)),
+    }
+}
+
+//Reads the reply to a request. A DLC that failed to start may send an
+//internal error event instead.
+fn read_reply(conn: &mut TcpStream) -> Result<V1Reply, Error> {
+    let pdu: serde_json::Value = read_pdu(conn).map_err(Error::CannotReadPDU)?;
+    if let Ok(V1Event::InternalError(e)) = serde_json::from_value(pdu.clone()) {
+        return Err(Error::InternalError(e));
+    }
+    serde_json::from_value(pdu)
+        .map_err(|e| Error::CannotReadPDU(ReadError::Deserialize(e)))
+}
+
+fn serve_reverse_tunnel(dlc: &DlcEndpoint, id: u64, host_addr: &str)
+-> Result<(), Error> {
+    let host_conn = TcpStream::connect(host_addr)
+        .map_err(|e| Error::CannotConnectToHost(host_addr.to_owned(), e))?;
+    let dlc_conn = request_dlc(dlc, &V1Request::Accept(id))?;
+    splice(host_conn, dlc_conn).map_err(Error::TunnelIO)
+}
+
+//Reads events from the control connection, handles the ones meant for
+//the library and forwards the rest to `Container::wait()`.
+fn read_events(mut dlc_conn: TcpStream, dlc: DlcEndpoint,
+    reverse_tunnels: HashMap<u16, String>,
+    unhealthy: Arc<Mutex<Option<String>>>,
+    listening: Arc<Mutex<Vec<u16>>>,
+    certificates: Arc<Mutex<Vec<V1Certificate>>>,
+    sender: Sender<Result<V1Event, ReadError>>) {
+    loop {
+        let res = read_pdu(&mut dlc_conn);
+        match &res {
+            Ok(V1Event::Unhealthy(reason)) => {
+                *unhealthy.lock().expect("Mutex poisoned") = Some(reason.clone());
+            },
+            //Liveness is checked from the beginning for a restarted entrypoint
+            Ok(V1Event::Healthy | V1Event::Restarting { .. }) => {
+                *unhealthy.lock().expect("Mutex poisoned") = None;
+            },
+            _ => (),
+        }
+        if let Ok(V1Event::Listening(ports)) = res {
+            *listening.lock().expect("Mutex poisoned") = ports;
+            continue;
+        }
+        if let Ok(V1Event::TlsCertificates(certs)) = res {
+            *certificates.lock().expect("Mutex poisoned") = certs;
+            continue;
+        }
+        if let Ok(V1Event::TunnelRequested { port, id }) = res {
+            let Some(host_addr) = reverse_tunnels.get(&port).cloned() else {
+                log::warn!("Unexpected reverse tunnel request for port {port}");
+                continue;
+            };
+            let dlc = dlc.clone();
+            std::thread::spawn(move || {
+                if let Err(e) = serve_reverse_tunnel(&dlc, id, &host_addr) {
+                    log::warn!("Reverse tunnel from port {port} \
+                        to {host_addr} failed: {e}");
+                }
+            });
+            continue;
+        }
+
+        let is_err = res.is_err();
+        if sender.send(res).is_err() || is_err {
+            break;
+        }
+    }
+}
+
 /// Error type for this module.
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
+    /// Parameters of the container are invalid.
+    #[error("Invalid container parameters: {0}")]
+    InvalidParams(String),
     /// Cannot create volume for DLC.
     #[error("Cannot create volume for DLC")]
     CannotCreateVolume(#[source] ExecError),
@@ -215,6 +933,13 @@ pub enum Error {
     /// Cannot start the container.
     #[error("Cannot start the container")]
     CannotStartContainer(ExecError),
+    /// Cannot inspect the container to attach to.
+    #[error("Cannot inspect the container")]
+    CannotInspectContainer(#[source] ExecError),
+    /// The container cannot be attached to, e.g. because it was not
+    /// created by Disposables.
+    #[error("Cannot attach to the container: {0}")]
+    NotAttachable(String),
     /// Cannot find the mapped port.
     #[error("Cannot find the mapped port")]
     CannotFindMappedPort(u16, ExecError),
@@ -227,6 +952,130 @@ pub enum Error {
     /// Cannot read data from the DLC port.
     #[error("Cannot read data from the DLC port")]
     CannotReadPDU(ReadError),
+    /// Cannot write data to the DLC port.
+    #[error("Cannot write data to the DLC port")]
+    CannotWritePDU(#[source] std::io::Error),
+    /// Cannot connect to the host address of a reverse tunnel.
+    #[error("Cannot connect to {0}")]
+    CannotConnectToHost(String, #[source] std::io::Error),
+    /// DLC refused a request.
+    #[error("DLC refused the request: {0}")]
+    RequestRefused(String),
+    /// Cannot listen for connections to forward on the host.
+    #[error("Cannot listen on the host")]
+    CannotListen(#[source] std::io::Error),
+    /// OS side error while forwarding data through a tunnel.
+    #[error("OS side error while forwarding data through tunnel")]
+    TunnelIO(#[source] std::io::Error),
+    /// The job did not finish before the deadline.
+    #[error("Job did not finish within {0:?}")]
+    JobDeadlineExceeded(Duration),
+    /// The job failed before its entrypoint could finish.
+    #[error("Job failed: {0:?}")]
+    JobFailed(V1Event),
+    /// The container did not reach the phase.
+    /// (see `Container::wait_for_phase()`)
+    #[error("Phase {0:?} was not reached: {1:?}")]
+    PhaseNotReached(String, V1Event),
+    /// DLC failed before it could report the failure as an event.
+    /// The message is taken from the container's logs.
+    #[error("DLC failed: {0}")]
+    InternalError(String),
+}
+
+/**
+ * Writer that feeds the stdin of a container's entrypoint.
+ * (see `Container::stdin()`)
+ *
+ * Dropping it closes the entrypoint's stdin.
+ */
+#[derive(Debug)]
+pub struct ContainerStdin {
+    conn: TcpStream,
+}
+
+impl ContainerStdin {
+    /**
+     * Closes the entrypoint's stdin, and waits until DLC has closed it.
+     */
+    pub fn close(mut self) -> Result<(), std::io::Error> {
+        self.conn.shutdown(Shutdown::Write)?;
+        //DLC closes the connection after closing stdin
+        let mut buf = [0_u8; 64];
+        while self.conn.read(&mut buf)? > 0 {}
+        Ok(())
+    }
+}
+
+/**
+ * Listener on the host that forwards connections to a Unix domain socket
+ * inside a container. (see `Container::forward_unix()`)
+ *
+ * Dropping it stops accepting connections, connections that were already
+ * accepted are kept open.
+ */
+#[derive(Debug)]
+pub struct UnixForward {
+    addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl UnixForward {
+    /**
+     * Returns the address the forwarded connections are accepted on.
+     */
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for UnixForward {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        //Wakes up the thread waiting for a connection
+        let _ = TcpStream::connect(self.addr);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Write for ContainerStdin {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.conn.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.conn.flush()
+    }
+}
+
+/**
+ * Result of running a container to completion.
+ * (see `ContainerParams::run_to_completion()`)
+ */
+#[derive(Debug)]
+pub struct JobOutput {
+    /// Exit code of the entrypoint.
+    /// (None if the entrypoint was terminated by a signal)
+    pub code: Option<i32>,
+    /// Lines written by the entrypoint to stdout.
+    pub stdout: String,
+    /// Lines written by the entrypoint to stderr.
+    pub stderr: String,
+    /// Time elapsed between the container being started and
+    /// the entrypoint exiting.
+    pub elapsed: Duration,
+}
+
+impl JobOutput {
+    /**
+     * Returns whether the entrypoint exited successfully.
+     */
+    pub fn success(&self) -> bool {
+        self.code == Some(0)
+    }
 }
 
 impl ContainerParams {
@@ -235,6 +1084,18 @@ impl ContainerParams {
      * using the given context.
      */
     pub fn create_using(&self, ctx: &Context) -> Result<Container, Error> {
+        self.create_with(ctx, &self.setup_msg)
+    }
+
+    fn create_with(&self, ctx: &Context, setup_msg: &V1SetupMsg)
+    -> Result<Container, Error> {
+        validate(setup_msg, &self.ports)?;
+        let token = new_token();
+        let setup_msg = &V1SetupMsg {
+            token: Some(token.clone()),
+            ..setup_msg.clone()
+        };
+
         //Find image entrypoint and command
         let image_exists = match ctx.podman(["image", "exists", &self.image]) {
             Ok(_) => true, 
@@ -276,22 +1137,26 @@ impl ContainerParams {
             .unwrap_or(image_meta.config.cmd.as_slice());
 
         //Setup message
-        let setup_msg = serde_json::to_string(&self.setup_msg)
+        let setup_msg_str = serde_json::to_string(setup_msg)
             .expect("Error serializing setup message");
 
         //Ports
-        let ports: Vec<u16>
-            = [DLC_PORT].iter().chain(&self.ports).cloned().collect();
+        let ports: Vec<(u16, Protocol)> = [(DLC_PORT, Protocol::Tcp)].iter()
+            .chain(&self.ports).cloned()
+            .chain(setup_msg.proxies.iter().map(|p| (p.listen, Protocol::Tcp)))
+            .collect();
 
         //Start container
         let mut args = Args::from(["run", "-d", "--rm",
             "-v", &format!("{}:{DLC_MOUNT_POINT}", ctx.volume()),
-            "-e", &format!("{V1_ENV_SETUP}={setup_msg}")]);
+            //The value is passed in the engine's environment, as it
+            //contains the token
+            "-e", V1_ENV_SETUP]);
         for (key, value) in &self.env {
             args.add("-e").add(format!("{key}={value}"));
         }
-        for p in &ports {
-            args.add("-p").add(p.to_string());
+        for (p, protocol) in &ports {
+            args.add("-p").add(format!("{p}/{}", protocol.as_str()));
         }
         args.add(format!("--entrypoint={}/dlc", ctx.dlc_install_dir()))
             .add(self.image.clone())
@@ -299,36 +1164,41 @@ impl ContainerParams {
             .extend(img_entrypoint)
             .extend(img_cmd);
         
-        let id = match ctx.podman(args.get()) {
+        let env = [(V1_ENV_SETUP, setup_msg_str.as_str())];
+        let id = match ctx.podman_with_env(args.get(), env) {
             Ok(id) => id,
             Err(_) => {
                 ctx.create_volume().map_err(Error::CannotCreateVolume)?;
-                ctx.podman(args).map_err(Error::CannotStartContainer)?
+                ctx.podman_with_env(args, env)
+                    .map_err(Error::CannotStartContainer)?
             }
         };
 
         //Create port map
-        let mut port_map = HashMap::<u16, String>::new();
-        for p in ports {
-            let output = ctx.podman(["port", &id, &format!("{p}")])
+        let mut port_map = HashMap::<(u16, Protocol), String>::new();
+        for (p, protocol) in ports {
+            let output = ctx.podman(["port", &id,
+                &format!("{p}/{}", protocol.as_str())])
                 .map_err(|e| Error::CannotFindMappedPort(p, e))?;
-            port_map.insert(p, output);
+            port_map.insert((p, protocol), output);
         }
+        remap_proxies(&mut port_map, &setup_msg.proxies);
 
         //Connect to DLC port
-        let addr_string = port_map.get(&DLC_PORT)
-            .expect("DLC port does not exist");
-        
-        let dlc_conn = try_use(addr_string.split_whitespace(), |x| {
-            TcpStream::connect(x).map_err(|e| (x.to_owned(), e))
-        }).map_err(Error::CannotConnectToDlc)?;
-        
-        Ok(Container {
-            ctx: ctx.clone(),
-            id,
-            port_map,
-            dlc_conn
-        })
+        let dlc = DlcEndpoint {
+            addr: port_map.get(&(DLC_PORT, Protocol::Tcp))
+                .expect("DLC port does not exist").clone(),
+            token,
+        };
+
+        let dlc_conn = request_dlc(&dlc, &V1Request::Attach).map_err(|e| {
+            find_internal_error(ctx, &id).map(Error::InternalError)
+                .unwrap_or(e)
+        })?;
+
+        Container::start(ctx, id, port_map, dlc, dlc_conn,
+            self.reverse_tunnels.clone(),
+            self.keep_on_failure.or_else(keep_on_failure_from_env))
     }
 
     /**
@@ -338,9 +1208,184 @@ impl ContainerParams {
     pub fn create(&self) -> Result<Container, Error> {
         self.create_using(Context::global())
     }
+
+    /**
+     * Runs a one-shot job container using the given context, and waits 
+     * for its entrypoint to exit.
+     *
+     * The container's output is captured and returned along with the exit
+     * code. If the entrypoint does not exit within `deadline`, the container
+     * is terminated and `Error::JobDeadlineExceeded` is returned.
+     */
+    pub fn run_to_completion_using(&self, ctx: &Context, deadline: Duration)
+    -> Result<JobOutput, Error> {
+        let mut setup_msg = self.setup_msg.clone();
+        setup_msg.forward_output = true;
+        let mut container = self.create_with(ctx, &setup_msg)?;
+
+        let start = Instant::now();
+        let mut stdout = String::new();
+        let mut stderr = String::new();
+        loop {
+            let remaining = deadline.checked_sub(start.elapsed())
+                .ok_or(Error::JobDeadlineExceeded(deadline))?;
+            let event = container.wait_timeout(remaining)?
+                .ok_or(Error::JobDeadlineExceeded(deadline))?;
+            match event {
+                V1Event::Output { stream, line } => {
+                    let buf = match stream {
+                        V1OutputStream::Stdout => &mut stdout,
+                        V1OutputStream::Stderr => &mut stderr,
+                    };
+                    buf.push_str(&line);
+                    buf.push('\n');
+                },
+                V1Event::Exited(code) => {
+                    return Ok(JobOutput {
+                        code,
+                        stdout,
+                        stderr,
+                        elapsed: start.elapsed(),
+                    });
+                },
+                V1Event::FailedToPrepare(_) | V1Event::FailedToStartEntrypoint(_)
+                    | V1Event::FailedTimeout
+                    | V1Event::FailedOutputMatch { .. }
+                    | V1Event::SidecarFailedTimeout(_)
+                    | V1Event::SidecarFailedToStart { .. }
+                    | V1Event::LifetimeExpired
+                    | V1Event::InternalError(_) => {
+                    return Err(Error::JobFailed(event));
+                },
+                //Other events do not stop the job
+                _ => (),
+            }
+        }
+    }
+
+    /**
+     * Runs a one-shot job container using the global context, and waits
+     * for its entrypoint to exit.
+     *
+     * ```rust
+     * # use disposables::ContainerParams;
+     * # use disposables::args::Args;
+     * # use std::time::Duration;
+     *
+     * let output = ContainerParams::new("docker.io/alpine")
+     *     .entrypoint(Args::from(["echo", "Hello"]))
+     *     .cmd(Args::new())
+     *     .run_to_completion(Duration::from_secs(60)).unwrap();
+     *
+     * assert!(output.success());
+     * assert_eq!(output.stdout, "Hello\n");
+     * ```
+     */
+    pub fn run_to_completion(&self, deadline: Duration)
+    -> Result<JobOutput, Error> {
+        self.run_to_completion_using(Context::global(), deadline)
+    }
 }
 
 impl Container {
+    //Starts reading events from an authenticated control connection.
+    fn start(ctx: &Context, id: String,
+        port_map: HashMap<(u16, Protocol), String>,
+        dlc: DlcEndpoint, dlc_conn: TcpStream,
+        reverse_tunnels: HashMap<u16, String>,
+        keep_on_failure: Option<Duration>) -> Result<Container, Error> {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let reader_conn = dlc_conn.try_clone()
+            .map_err(|e| Error::CannotConnectToDlc(
+                    vec![(dlc.addr.clone(), e)]))?;
+        let reader_dlc = dlc.clone();
+        let unhealthy = Arc::new(Mutex::new(None));
+        let reader_unhealthy = unhealthy.clone();
+        let listening = Arc::new(Mutex::new(Vec::new()));
+        let reader_listening = listening.clone();
+        let certificates = Arc::new(Mutex::new(Vec::new()));
+        let reader_certificates = certificates.clone();
+        std::thread::spawn(move || {
+            read_events(reader_conn, reader_dlc, reverse_tunnels,
+                reader_unhealthy, reader_listening, reader_certificates,
+                sender);
+        });
+
+        Ok(Container {
+            ctx: ctx.clone(),
+            id,
+            port_map,
+            dlc,
+            dlc_conn,
+            events: Mutex::new(receiver),
+            unhealthy,
+            listening,
+            certificates,
+            keep_on_failure,
+            keep: false,
+        })
+    }
+
+    /**
+     * Attaches to a running container using the given context, taking over
+     * from the `Container` that created it.
+     *
+     * The container must have been created with `ContainerParams::linger()`
+     * if the previous `Container` might be dropped before attaching.
+     * Events that were not delivered to the previous `Container` are
+     * received first. Reverse tunnels are not served by the attached
+     * `Container`, as their host addresses are only known to the creator.
+     */
+    pub fn attach_using(ctx: &Context, id: impl Into<String>)
+    -> Result<Container, Error> {
+        let id = id.into();
+
+        //Recover the setup message from the container's environment
+        let env = ctx.podman(["inspect", "--format", "{{json .Config.Env}}",
+            &id]).map_err(Error::CannotInspectContainer)?;
+        let env: Vec<String> = serde_json::from_str(&env)
+            .map_err(|e| Error::NotAttachable(
+                format!("Cannot parse environment: {e}")))?;
+        let setup_msg = env.iter()
+            .find_map(|var| var.strip_prefix(V1_ENV_SETUP)?.strip_prefix('='))
+            .ok_or_else(|| Error::NotAttachable(
+                format!("{V1_ENV_SETUP} is not set")))?;
+        let setup_msg: V1SetupMsg = serde_json::from_str(setup_msg)
+            .map_err(|e| Error::NotAttachable(
+                format!("Cannot parse {V1_ENV_SETUP}: {e}")))?;
+
+        let output = ctx.podman(["port", &id])
+            .map_err(|e| Error::CannotFindMappedPort(DLC_PORT, e))?;
+        let mut port_map = parse_port_list(&output)?;
+        remap_proxies(&mut port_map, &setup_msg.proxies);
+
+        let dlc = DlcEndpoint {
+            addr: port_map.get(&(DLC_PORT, Protocol::Tcp))
+                .ok_or_else(|| Error::NotAttachable(
+                    format!("Port {DLC_PORT} is not published")))?
+                .clone(),
+            token: setup_msg.token.unwrap_or_default(),
+        };
+        let dlc_conn = request_dlc(&dlc, &V1Request::Attach).map_err(|e| {
+            match e {
+                //DLC only refuses to attach when it failed to start
+                Error::RequestRefused(e) => Error::InternalError(e),
+                e => e,
+            }
+        })?;
+
+        Container::start(ctx, id, port_map, dlc, dlc_conn, HashMap::new(),
+            keep_on_failure_from_env())
+    }
+
+    /**
+     * Attaches to a running container using the global context.
+     * (see `Container::attach_using()`)
+     */
+    pub fn attach(id: impl Into<String>) -> Result<Container, Error> {
+        Container::attach_using(Context::global(), id)
+    }
+
     /**
      * Returns the container's ID. The container can be identified
      * by Docker/Podman using the ID.
@@ -369,16 +1414,305 @@ impl Container {
 
     /**
      * Waits for events from the running container.
+     *
+     * Events handled by `Container` itself are not returned. In particular,
+     * the ports found by a `ContainerParams::wait_for_listening()` condition
+     * arrive in a separate `V1Event::Listening` just before `V1Event::Ready`,
+     * and are available from `Container::listening_ports()` instead.
+     * Likewise, the certificates found by `ContainerParams::wait_for_tls()`
+     * conditions arrive in `V1Event::TlsCertificates`, and are available from
+     * `Container::tls_certificates()`.
      */
     pub fn wait(&mut self) -> Result<V1Event, Error> {
-        read_pdu(&mut self.dlc_conn).map_err(Error::CannotReadPDU)
+        let events = self.events.get_mut().expect("Mutex poisoned");
+        match events.recv() {
+            Ok(res) => res.map_err(|e| self.read_failed(e)),
+            //Reader has already reported the error and stopped
+            Err(_) => Err(self.read_failed(ReadError::System(
+                std::io::ErrorKind::UnexpectedEof.into()))),
+        }
+    }
+
+    /**
+     * Waits for the container to reach the phase named `name`.
+     * (see `ContainerParams::phase()`)
+     *
+     * Events received before the phase is reached are discarded, so phases
+     * have to be waited for in order. Returns `Error::PhaseNotReached` if
+     * the container becomes ready, fails or exits without reaching
+     * the phase.
+     */
+    pub fn wait_for_phase(&mut self, name: &str) -> Result<(), Error> {
+        loop {
+            match self.wait()? {
+                V1Event::PhaseReached(phase) if phase == name => return Ok(()),
+                V1Event::PhaseReached(_) | V1Event::Output { .. }
+                    | V1Event::OutputError { .. } | V1Event::Healthy
+                    | V1Event::Unhealthy(_) | V1Event::Restarting { .. }
+                    | V1Event::SidecarReady(_)
+                    | V1Event::SidecarFailedTimeout(_)
+                    | V1Event::SidecarFailedToStart { .. }
+                    | V1Event::SidecarExited { .. } => (),
+                event => return Err(Error::PhaseNotReached(name.to_owned(),
+                    event)),
+            }
+        }
+    }
+
+    /**
+     * Waits for events from the running container, for at most `timeout`.
+     *
+     * Returns `None` if no event was received within the timeout.
+     */
+    pub fn wait_timeout(&mut self, timeout: Duration)
+    -> Result<Option<V1Event>, Error> {
+        let events = self.events.get_mut().expect("Mutex poisoned");
+        match events.recv_timeout(timeout) {
+            Ok(res) => res.map(Some).map_err(|e| self.read_failed(e)),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            //Reader has already reported the error and stopped
+            Err(RecvTimeoutError::Disconnected) => Err(self.read_failed(
+                ReadError::System(std::io::ErrorKind::UnexpectedEof.into()))),
+        }
+    }
+
+    //When the event connection is lost, DLC may have left the reason
+    //in the container's logs.
+    fn read_failed(&self, e: ReadError) -> Error {
+        find_internal_error(&self.ctx, &self.id)
+            .map(Error::InternalError)
+            .unwrap_or(Error::CannotReadPDU(e))
     }
 
     /**
-     * Returns the port mapping for the given port.
+     * Returns the result of the liveness checks, as of the last
+     * `V1Event::Healthy` or `V1Event::Unhealthy` event received from DLC.
+     *
+     * Returns the reason of the failure if the container is unhealthy.
+     * The events are tracked in the background, so there is no need to
+     * call `wait()` to receive them.
+     */
+    pub fn health(&self) -> Result<(), String> {
+        match &*self.unhealthy.lock().expect("Mutex poisoned") {
+            Some(reason) => Err(reason.clone()),
+            None => Ok(()),
+        }
+    }
+
+    /**
+     * Returns the TCP ports the entrypoint and its descendants were found
+     * listening on, when the container has a condition added by
+     * `ContainerParams::wait_for_listening()`. The ports are known once
+     * `V1Event::Ready` has been received.
+     */
+    pub fn listening_ports(&self) -> Vec<u16> {
+        self.listening.lock().expect("Mutex poisoned").clone()
+    }
+
+    /**
+     * Returns the certificates presented by the ports of the conditions
+     * added by `ContainerParams::wait_for_tls()`. The certificates are known
+     * once `V1Event::Ready` has been received.
+     */
+    pub fn tls_certificates(&self) -> Vec<V1Certificate> {
+        self.certificates.lock().expect("Mutex poisoned").clone()
+    }
+
+    /**
+     * Returns the port mapping for the given TCP port.
      */
     pub fn port(&self, port: u16) -> Option<Vec<&str>> {
-        self.port_map.get(&port).map(|x| x.split_whitespace().collect())
+        self.mapped_port(port, Protocol::Tcp)
+    }
+
+    /**
+     * Returns the port mapping for the given UDP port, published using
+     * `ContainerParams::udp_port()`.
+     */
+    pub fn udp_port(&self, port: u16) -> Option<Vec<&str>> {
+        self.mapped_port(port, Protocol::Udp)
+    }
+
+    fn mapped_port(&self, port: u16, protocol: Protocol) -> Option<Vec<&str>> {
+        self.port_map.get(&(port, protocol))
+            .map(|x| x.split_whitespace().collect())
+    }
+
+    /**
+     * Opens a TCP connection to the given port inside the container.
+     *
+     * The connection is tunnelled through DLC's port, so the port does not
+     * need to be forwarded to the host using `ContainerParams::port()`.
+     *
+     * ```rust
+     * # use disposables::ContainerParams;
+     * # use disposables::protocol::V1Event;
+     * # use std::io::{Read, Write};
+     *
+     * let mut container = ContainerParams::new("docker.io/nginx:alpine")
+     *     .wait_for_port(80)
+     *     .create().unwrap();
+     *
+     * assert!(matches!(container.wait().unwrap(), V1Event::Ready),
+     *     "Nginx failed to start: {}", container.logs().unwrap());
+     *
+     * let mut conn = container.connect(80).unwrap();
+     * write!(conn, "GET / HTTP/1.0\nHost: localhost\n\n").unwrap();
+     * let mut response = String::new();
+     * conn.read_to_string(&mut response).unwrap();
+     * ```
+     */
+    pub fn connect(&self, port: u16) -> Result<TcpStream, Error> {
+        request_dlc(&self.dlc, &V1Request::Connect(port))
+    }
+
+    /**
+     * Connects to the Unix domain socket at `path` inside the container,
+     * through DLC.
+     */
+    pub fn connect_unix(&self, path: impl Into<String>)
+    -> Result<TcpStream, Error> {
+        request_dlc(&self.dlc, &V1Request::ConnectUnix(path.into()))
+    }
+
+    /**
+     * Listens on a local TCP port on the host, and forwards the connections
+     * to the Unix domain socket at `path` inside the container, for clients
+     * that need an address to connect to.
+     *
+     * ```rust
+     * # use disposables::ContainerParams;
+     * # use disposables::protocol::V1Event;
+     *
+     * let mut container = ContainerParams::new("docker.io/postgres:alpine")
+     *     .env("POSTGRES_PASSWORD", "postgres")
+     *     .wait_for_unix_socket("/var/run/postgresql/.s.PGSQL.5432")
+     *     .create().unwrap();
+     *
+     * assert!(matches!(container.wait().unwrap(), V1Event::Ready),
+     *     "Postgres failed to start: {}", container.logs().unwrap());
+     *
+     * let forward = container
+     *     .forward_unix("/var/run/postgresql/.s.PGSQL.5432").unwrap();
+     * let url = format!("postgres://postgres@{}/postgres", forward.addr());
+     * ```
+     */
+    pub fn forward_unix(&self, path: impl Into<String>)
+    -> Result<UnixForward, Error> {
+        let path = path.into();
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .map_err(Error::CannotListen)?;
+        let addr = listener.local_addr().map_err(Error::CannotListen)?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        let dlc = self.dlc.clone();
+        let thread = std::thread::spawn(move || {
+            for conn in listener.incoming() {
+                if thread_stop.load(Ordering::SeqCst) {
+                    break;
+                }
+                let conn = match conn {
+                    Ok(conn) => conn,
+                    Err(e) => {
+                        log::warn!("Unable to accept connection: {e}");
+                        continue;
+                    },
+                };
+                let dlc = dlc.clone();
+                let path = path.clone();
+                std::thread::spawn(move || {
+                    let res = request_dlc(&dlc,
+                        &V1Request::ConnectUnix(path.clone()))
+                        .and_then(|dlc_conn| splice(conn, dlc_conn)
+                            .map_err(Error::TunnelIO));
+                    if let Err(e) = res {
+                        log::warn!("Forwarding connection to {path} failed: {e}");
+                    }
+                });
+            }
+        });
+        Ok(UnixForward { addr, stop, thread: Some(thread) })
+    }
+
+    /**
+     * Replaces the faults injected into connections to a port forwarded
+     * using `ContainerParams::proxy_port()`.
+     *
+     * The new toxics apply to existing connections as well as new ones, and
+     * are in effect by the time this function returns.
+     *
+     * ```rust
+     * # use disposables::ContainerParams;
+     * # use disposables::protocol::{V1Event, V1Toxic, V1Toxics};
+     *
+     * let mut container = ContainerParams::new("docker.io/nginx:alpine")
+     *     .proxy_port(80)
+     *     .wait_for_port(80)
+     *     .create().unwrap();
+     *
+     * assert!(matches!(container.wait().unwrap(), V1Event::Ready),
+     *     "Nginx failed to start: {}", container.logs().unwrap());
+     *
+     * //Responses from nginx now take at least 200ms to arrive
+     * container.set_toxics(80, V1Toxics {
+     *     upstream: vec![],
+     *     downstream: vec![V1Toxic::Latency { latency_ms: 200, jitter_ms: 0 }],
+     * }).unwrap();
+     * ```
+     */
+    pub fn set_toxics(&self, port: u16, toxics: V1Toxics) -> Result<(), Error> {
+        request_dlc(&self.dlc, &V1Request::SetToxics { port, toxics })
+            .map(drop)
+    }
+
+    /**
+     * Opens the stdin of the container's entrypoint for writing.
+     * The container must have been created with `ContainerParams::pipe_stdin()`,
+     * and stdin can only be opened once.
+     *
+     * ```rust
+     * # use disposables::ContainerParams;
+     * # use disposables::args::Args;
+     * # use disposables::protocol::V1Event;
+     * # use std::io::Write;
+     *
+     * let mut container = ContainerParams::new("docker.io/alpine")
+     *     .entrypoint(Args::from(["sh", "-c", "read name; echo \"Hi $name\""]))
+     *     .cmd(Args::new())
+     *     .pipe_stdin()
+     *     .wait_for_stdout("Hi there")
+     *     .create().unwrap();
+     *
+     * let mut stdin = container.stdin().unwrap();
+     * writeln!(stdin, "there").unwrap();
+     * stdin.close().unwrap();
+     *
+     * assert!(matches!(container.wait().unwrap(), V1Event::Ready),
+     *     "Unexpected output: {}", container.logs().unwrap());
+     * ```
+     */
+    pub fn stdin(&self) -> Result<ContainerStdin, Error> {
+        request_dlc(&self.dlc, &V1Request::Stdin)
+            .map(|conn| ContainerStdin { conn })
+    }
+
+    /**
+     * Changes the size of the pseudo-terminal the entrypoint runs on.
+     * The container must have been created with `ContainerParams::pty()`.
+     */
+    pub fn resize(&self, rows: u16, cols: u16) -> Result<(), Error> {
+        request_dlc(&self.dlc, &V1Request::Resize(V1TermSize { rows, cols }))
+            .map(drop)
+    }
+
+    /**
+     * Keeps the container running after this `Container` is dropped, so that
+     * it can be inspected, e.g. with `podman exec`. The container is kept for
+     * the duration set by `ContainerParams::keep_on_failure()`,
+     * or 10 minutes.
+     */
+    pub fn keep(&mut self) {
+        self.keep = true;
     }
 
     /**
@@ -404,3 +1738,27 @@ impl Container {
     }
 }
 
+impl Drop for Container {
+    fn drop(&mut self) {
+        let keep = if self.keep {
+            Some(self.keep_on_failure.unwrap_or(DEFAULT_KEEP_DURATION))
+        } else if std::thread::panicking() {
+            self.keep_on_failure
+        } else {
+            None
+        };
+        if let Some(duration) = keep {
+            let keep_s = duration.as_secs();
+            match request_dlc(&self.dlc, &V1Request::Keep(keep_s)) {
+                Ok(_) => eprintln!("Keeping container {id} for {keep_s}s. \
+                    To get a shell in it, run:\n    {engine} exec -it {id} sh",
+                    id = self.id, engine = self.ctx.engine()),
+                Err(e) => log::warn!("Cannot keep container {}: {e}", self.id),
+            }
+        }
+
+        //The event reader holds a clone of the connection, so dropping
+        //our handle alone does not close it.
+        let _ = self.dlc_conn.shutdown(Shutdown::Both);
+    }
+}
@@ -51,9 +51,15 @@ pub enum ExecError {
 }
 
 fn run(arg0: impl Into<String>, args: impl Into<Args>) -> Result<String, ExecError> {
+    run_with_env(arg0, args, [])
+}
+
+fn run_with_env<'a>(arg0: impl Into<String>, args: impl Into<Args>,
+    env: impl IntoIterator<Item = (&'a str, &'a str)>)
+-> Result<String, ExecError> {
     let arg0 = arg0.into();
     let args = args.into();
-    let output = Command::new(&arg0).args(args.get())
+    let output = Command::new(&arg0).args(args.get()).envs(env)
         .stdout(Stdio::piped()).stderr(Stdio::piped()).output()
         .map_err(ExecError::System)?;
     if ! output.status.success() {
@@ -219,6 +225,14 @@ impl Context {
         run(&self.engine, args)
     }
 
+    //Like `podman()`, with extra environment variables for the engine.
+    //Values passed this way do not show up on the engine's command line.
+    pub(crate) fn podman_with_env<'a>(&self, args: impl Into<Args>,
+        env: impl IntoIterator<Item = (&'a str, &'a str)>)
+    -> Result<String, ExecError> {
+        run_with_env(&self.engine, args, env)
+    }
+
     pub(crate) fn dlc_install_dir(&self) -> String {
         let mut res = format!("{DLC_MOUNT_POINT}/");
         for c in self.image.chars() {
@@ -82,4 +82,5 @@ pub mod protocol {
     pub use disposables_protocol::*;
 }
 pub use context::Context;
-pub use container::{Container, ContainerParams};
+pub use container::{Container, ContainerParams, ContainerStdin, JobOutput};
+pub use container::UnixForward;
@@ -17,9 +17,14 @@
  * along with Disposables. If not, see <https://www.gnu.org/licenses/>. 
  */
 
+#[cfg(test)]
+mod lifecycle;
+
 #[cfg(test)]
 mod nginx;
 
 #[cfg(test)]
 mod postgres;
 
+#[cfg(test)]
+mod tunnel;
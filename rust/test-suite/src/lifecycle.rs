@@ -0,0 +1,441 @@
+/*
+ * Copyright 2024 Akash Rawal
+ *
+ * This file is part of Disposables.
+ *
+ * Disposables is free software: you can redistribute it and/or modify it under 
+ * the terms of the GNU General Public License as published by the 
+ * Free Software Foundation, either version 3 of the License, or 
+ * (at your option) any later version.
+ * 
+ * Disposables is distributed in the hope that it will be useful, 
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of 
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. 
+ * See the GNU General Public License for more details.
+ * 
+ * You should have received a copy of the GNU General Public License 
+ * along with Disposables. If not, see <https://www.gnu.org/licenses/>. 
+ */
+
+use std::io::Write;
+use std::net::UdpSocket;
+use std::panic::AssertUnwindSafe;
+use std::time::Duration;
+
+use disposables::args::Args;
+use disposables::container::{Container, ContainerParams, Error};
+use disposables::protocol::{V1Event, V1RestartPolicy, V1Sidecar, V1WaitCondition};
+
+#[test]
+fn unhealthy_container_is_killed() {
+    drop(env_logger::try_init());
+
+    log::info!("Creating container...");
+    let mut container = ContainerParams::new("docker.io/alpine")
+        .entrypoint(Args::from(["sh", "-c",
+            "nc -lk -p 8080 -e true & sleep 3; kill $!; sleep 60"]))
+        .wait_for_port(8080)
+        .liveness(V1WaitCondition::Port(8080))
+        .liveness_interval(200)
+        .liveness_failure_threshold(2)
+        .kill_when_unhealthy(5)
+        .create().unwrap();
+
+    log::info!("Container created, waiting...");
+    let event = container.wait();
+    assert!(matches!(event, Ok(V1Event::Ready)),
+        "Container start failed: {event:?}, logs: {}", container.logs().unwrap());
+    assert!(container.health().is_ok());
+
+    let event = container.wait();
+    assert!(matches!(event, Ok(V1Event::Unhealthy(_))),
+        "Unexpected event: {event:?}, logs: {}", container.logs().unwrap());
+    assert!(container.health().is_err());
+
+    let event = container.wait();
+    assert!(matches!(event, Ok(V1Event::Exited(_))),
+        "Unexpected event: {event:?}, logs: {}", container.logs().unwrap());
+}
+
+#[test]
+fn invalid_liveness_settings_are_rejected() {
+    let res = ContainerParams::new("docker.io/alpine")
+        .liveness(V1WaitCondition::Port(8080))
+        .liveness_interval(0)
+        .create();
+    assert!(matches!(res, Err(Error::InvalidParams(_))),
+        "Unexpected result: {:?}", res.map(|c| c.id().to_owned()));
+
+    let res = ContainerParams::new("docker.io/alpine")
+        .liveness(V1WaitCondition::Port(8080))
+        .liveness_failure_threshold(3)
+        .kill_when_unhealthy(2)
+        .create();
+    assert!(matches!(res, Err(Error::InvalidParams(_))),
+        "Unexpected result: {:?}", res.map(|c| c.id().to_owned()));
+}
+
+#[test]
+fn failure_pattern_in_stderr() {
+    drop(env_logger::try_init());
+
+    log::info!("Creating container...");
+    let mut container = ContainerParams::new("docker.io/alpine")
+        .entrypoint(Args::from(["sh", "-c",
+            "echo 'bind: Address already in use' >&2; sleep 60"]))
+        .wait_for_stdout("Server started")
+        .fail_on_output("Address already in use")
+        .create().unwrap();
+
+    log::info!("Container created, waiting...");
+    let event = container.wait();
+    assert!(matches!(&event, Ok(V1Event::FailedOutputMatch { line, .. })
+            if line == "bind: Address already in use"),
+        "Unexpected event: {event:?}, logs: {}", container.logs().unwrap());
+}
+
+#[test]
+fn job_runs_to_completion() {
+    drop(env_logger::try_init());
+
+    let output = ContainerParams::new("docker.io/alpine")
+        .entrypoint(Args::from(["sh", "-c",
+            "echo first; echo second; echo problem >&2; exit 3"]))
+        .run_to_completion(Duration::from_secs(60)).unwrap();
+
+    assert_eq!(output.code, Some(3));
+    assert_eq!(output.stdout, "first\nsecond\n");
+    assert_eq!(output.stderr, "problem\n");
+}
+
+#[test]
+fn job_exceeding_deadline_fails() {
+    drop(env_logger::try_init());
+
+    let res = ContainerParams::new("docker.io/alpine")
+        .entrypoint(Args::from(["sleep", "60"]))
+        .cmd(Args::new())
+        .run_to_completion(Duration::from_secs(2));
+
+    assert!(matches!(res, Err(Error::JobDeadlineExceeded(_))),
+        "Unexpected result: {res:?}");
+}
+
+#[test]
+fn listen_failure_is_reported() {
+    drop(env_logger::try_init());
+
+    //DLC listens on port 4 itself, so the tunnel cannot
+    let mut container = ContainerParams::new("docker.io/alpine")
+        .entrypoint(Args::from(["sleep", "60"]))
+        .cmd(Args::new())
+        .reverse_tunnel(4, "127.0.0.1:1")
+        .create().unwrap();
+
+    let event = container.wait();
+    assert!(matches!(&event, Ok(V1Event::InternalError(e))
+            if e.contains("Unable to listen")),
+        "Unexpected event: {event:?}, logs: {}", container.logs().unwrap());
+}
+
+#[test]
+fn zero_timeout_is_rejected() {
+    let res = ContainerParams::new("docker.io/alpine")
+        .wait_for_port(8080)
+        .probe_connect_timeout(0)
+        .create();
+    assert!(matches!(res, Err(Error::InvalidParams(_))),
+        "Unexpected result: {:?}", res.map(|c| c.id().to_owned()));
+}
+
+#[test]
+fn sidecar_listening_condition_is_rejected() {
+    let res = ContainerParams::new("docker.io/alpine")
+        .sidecar(V1Sidecar {
+            name: "helper".into(),
+            argv: vec!["sleep".into(), "60".into()],
+            wait_for: vec![V1WaitCondition::Listening(vec![8080])],
+            stdout_tag: None,
+            stderr_tag: None,
+        })
+        .create();
+    assert!(matches!(res, Err(Error::InvalidParams(_))),
+        "Unexpected result: {:?}", res.map(|c| c.id().to_owned()));
+}
+
+#[test]
+fn container_can_be_reattached() {
+    drop(env_logger::try_init());
+
+    let mut container = ContainerParams::new("docker.io/alpine")
+        .entrypoint(Args::from(["sh", "-c", "echo started; sleep 3; exit 3"]))
+        .cmd(Args::new())
+        .wait_for_stdout("started")
+        .linger(10)
+        .create().unwrap();
+
+    let event = container.wait();
+    assert!(matches!(event, Ok(V1Event::Ready)),
+        "Container start failed: {event:?}, logs: {}", container.logs().unwrap());
+
+    //The container outlives the first handle, and the exit is delivered
+    //to the second one.
+    let id = container.id().to_owned();
+    drop(container);
+    let mut container = Container::attach(&id).unwrap();
+
+    let event = container.wait();
+    assert!(matches!(event, Ok(V1Event::Exited(Some(3)))),
+        "Unexpected event: {event:?}, logs: {}", container.logs().unwrap());
+}
+
+#[test]
+fn kept_container_outlives_its_handle() {
+    drop(env_logger::try_init());
+
+    let mut container = ContainerParams::new("docker.io/alpine")
+        .entrypoint(Args::from(["sleep", "60"]))
+        .cmd(Args::new())
+        .keep_on_failure(Duration::from_secs(10))
+        .create().unwrap();
+
+    let id = container.id().to_owned();
+    container.keep();
+    drop(container);
+
+    //Dropping the attached handle without keeping it stops the container
+    let container = Container::attach(&id);
+    assert!(container.is_ok(), "Cannot attach: {:?}", container.err());
+}
+
+#[test]
+fn container_is_kept_when_dropped_during_panic() {
+    drop(env_logger::try_init());
+
+    let container = ContainerParams::new("docker.io/alpine")
+        .entrypoint(Args::from(["sleep", "60"]))
+        .cmd(Args::new())
+        .keep_on_failure(Duration::from_secs(10))
+        .create().unwrap();
+
+    let id = container.id().to_owned();
+    let res = std::panic::catch_unwind(AssertUnwindSafe(move || {
+        let _container = container;
+        panic!("Simulated test failure");
+    }));
+    assert!(res.is_err());
+
+    let container = Container::attach(&id);
+    assert!(container.is_ok(), "Cannot attach: {:?}", container.err());
+}
+
+#[test]
+fn stdin_is_forwarded() {
+    drop(env_logger::try_init());
+
+    let mut container = ContainerParams::new("docker.io/alpine")
+        .entrypoint(Args::from(["sh", "-c", "n=$(wc -l); echo \"lines $((n))\""]))
+        .cmd(Args::new())
+        .pipe_stdin()
+        .wait_for_stdout("lines 3")
+        .create().unwrap();
+
+    let mut stdin = container.stdin().unwrap();
+    stdin.write_all(b"one\ntwo\nthree\n").unwrap();
+    stdin.close().unwrap();
+
+    let event = container.wait();
+    assert!(matches!(event, Ok(V1Event::Ready)),
+        "Unexpected event: {event:?}, logs: {}", container.logs().unwrap());
+    assert!(container.stdin().is_err());
+}
+
+#[test]
+fn entrypoint_runs_on_pty() {
+    drop(env_logger::try_init());
+
+    let script = "test -t 0 || exit 1; \
+        while true; do echo \"size $(stty size)\"; sleep 0.2; done";
+    let mut container = ContainerParams::new("docker.io/alpine")
+        .entrypoint(Args::from(["sh", "-c", script]))
+        .cmd(Args::new())
+        .pty(24, 80)
+        .wait_for_stdout("size 30 100")
+        .create().unwrap();
+
+    container.resize(30, 100).unwrap();
+
+    let event = container.wait();
+    assert!(matches!(event, Ok(V1Event::Ready)),
+        "Unexpected event: {event:?}, logs: {}", container.logs().unwrap());
+}
+
+#[test]
+fn failed_entrypoint_is_restarted() {
+    drop(env_logger::try_init());
+
+    //Fails on the first run only
+    let script = "if [ ! -e /tmp/started ]; then touch /tmp/started; exit 3; fi; \
+        echo up; sleep 1000";
+    let mut container = ContainerParams::new("docker.io/alpine")
+        .entrypoint(Args::from(["sh", "-c", script]))
+        .cmd(Args::new())
+        .restart(V1RestartPolicy::OnFailure(1))
+        .restart_backoff(100)
+        .wait_for_stdout("up")
+        .create().unwrap();
+
+    let event = container.wait();
+    assert!(matches!(event, Ok(V1Event::Restarting { code: Some(3), count: 1 })),
+        "Unexpected event: {event:?}, logs: {}", container.logs().unwrap());
+    let event = container.wait();
+    assert!(matches!(event, Ok(V1Event::Ready)),
+        "Unexpected event: {event:?}, logs: {}", container.logs().unwrap());
+}
+
+#[test]
+fn sidecar_runs_alongside_entrypoint() {
+    drop(env_logger::try_init());
+
+    let mut container = ContainerParams::new("docker.io/alpine")
+        .entrypoint(Args::from(["sleep", "1000"]))
+        .cmd(Args::new())
+        .sidecar(V1Sidecar {
+            name: "helper".into(),
+            argv: vec!["sh".into(), "-c".into(), "echo helper up; exit 2".into()],
+            wait_for: vec![V1WaitCondition::Stdout("helper up".into())],
+            stdout_tag: None,
+            stderr_tag: None,
+        })
+        .create().unwrap();
+
+    let event = container.wait();
+    assert!(matches!(&event, Ok(V1Event::SidecarReady(name)) if name == "helper"),
+        "Unexpected event: {event:?}, logs: {}", container.logs().unwrap());
+    let event = container.wait();
+    assert!(matches!(&event,
+            Ok(V1Event::SidecarExited { name, code: Some(2) }) if name == "helper"),
+        "Unexpected event: {event:?}, logs: {}", container.logs().unwrap());
+}
+
+#[test]
+fn container_is_stopped_after_max_lifetime() {
+    drop(env_logger::try_init());
+
+    let mut container = ContainerParams::new("docker.io/alpine")
+        .entrypoint(Args::from(["sleep", "1000"]))
+        .cmd(Args::new())
+        .max_lifetime(2)
+        .create().unwrap();
+
+    let event = container.wait();
+    assert!(matches!(event, Ok(V1Event::LifetimeExpired)),
+        "Unexpected event: {event:?}, logs: {}", container.logs().unwrap());
+    let event = container.wait();
+    assert!(matches!(event, Ok(V1Event::Exited(_))),
+        "Unexpected event: {event:?}, logs: {}", container.logs().unwrap());
+}
+
+#[test]
+fn listening_ports_are_detected() {
+    drop(env_logger::try_init());
+
+    let mut container = ContainerParams::new("docker.io/alpine")
+        .entrypoint(Args::from(["sh", "-c", "sleep 1; nc -l -p 8080"]))
+        .cmd(Args::new())
+        .wait_for_listening([])
+        .create().unwrap();
+
+    let event = container.wait();
+    assert!(matches!(event, Ok(V1Event::Ready)),
+        "Unexpected event: {event:?}, logs: {}", container.logs().unwrap());
+    assert_eq!(container.listening_ports(), [8080]);
+}
+
+#[test]
+fn file_conditions_are_waited_for() {
+    drop(env_logger::try_init());
+
+    let script = "sleep 1; touch /tmp/app.pid; \
+        echo booting > /tmp/app.log; echo 'Started app' >> /tmp/app.log; \
+        sleep 1000";
+    let mut container = ContainerParams::new("docker.io/alpine")
+        .entrypoint(Args::from(["sh", "-c", script]))
+        .cmd(Args::new())
+        .wait_for_path("/tmp/app.pid")
+        .wait_for_file("/tmp/app.log", "Started app")
+        .create().unwrap();
+
+    let event = container.wait();
+    assert!(matches!(event, Ok(V1Event::Ready)),
+        "Unexpected event: {event:?}, logs: {}", container.logs().unwrap());
+}
+
+#[test]
+fn udp_port_is_waited_for_and_published() {
+    drop(env_logger::try_init());
+
+    let script = "sleep 1; while true; do nc -u -l -p 5353 -e echo pong; done";
+    let mut container = ContainerParams::new("docker.io/alpine")
+        .entrypoint(Args::from(["sh", "-c", script]))
+        .cmd(Args::new())
+        .udp_port(5353)
+        .wait_for_udp(5353, "ping", "pong")
+        .create().unwrap();
+
+    let event = container.wait();
+    assert!(matches!(event, Ok(V1Event::Ready)),
+        "Unexpected event: {event:?}, logs: {}", container.logs().unwrap());
+
+    let addr = container.udp_port(5353).unwrap()[0].to_owned();
+    let socket = UdpSocket::bind("0.0.0.0:0").unwrap();
+    socket.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+    let mut buf = [0; 16];
+    //nc is restarted between datagrams, so retry a few times
+    let reply = (0..10).find_map(|_| {
+        socket.send_to(b"ping", &addr).unwrap();
+        socket.recv(&mut buf).ok()
+    }).expect("No reply from the UDP port");
+    assert_eq!(&buf[..reply], b"pong\n");
+}
+
+#[test]
+fn grpc_health_is_waited_for() {
+    drop(env_logger::try_init());
+
+    let image = "registry.k8s.io/e2e-test-images/agnhost:2.47";
+    let mut container = ContainerParams::new(image)
+        .cmd(Args::from(["grpc-health-checking", "--port", "5000",
+            "--service", "db"]))
+        .wait_for_grpc(5000)
+        .wait_for_grpc_service(5000, "db")
+        .create().unwrap();
+
+    let event = container.wait();
+    assert!(matches!(event, Ok(V1Event::Ready)),
+        "Unexpected event: {event:?}, logs: {}", container.logs().unwrap());
+}
+
+#[test]
+fn phases_are_reached_in_order() {
+    drop(env_logger::try_init());
+
+    let script = "touch /tmp/warm; sleep 1; echo migrated; sleep 1000";
+    let mut container = ContainerParams::new("docker.io/alpine")
+        .entrypoint(Args::from(["sh", "-c", script]))
+        .cmd(Args::new())
+        .phase("migrations")
+        .wait_for_stdout("migrated")
+        .phase("cache")
+        .wait_for_path("/tmp/warm")
+        .create().unwrap();
+
+    container.wait_for_phase("migrations").unwrap();
+    let event = container.wait();
+    assert!(matches!(&event, Ok(V1Event::PhaseReached(phase)) if phase == "cache"),
+        "Unexpected event: {event:?}, logs: {}", container.logs().unwrap());
+    //Ready is sent after the last phase
+    let res = container.wait_for_phase("unknown");
+    assert!(matches!(res, Err(Error::PhaseNotReached(_, V1Event::Ready))),
+        "Unexpected result: {res:?}");
+}
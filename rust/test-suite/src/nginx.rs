@@ -20,8 +20,8 @@
 use std::io::{Read, Write};
 use std::net::TcpStream;
 
-use disposables::container::ContainerParams;
-use disposables::protocol::V1Event;
+use disposables::container::{ContainerParams, Error};
+use disposables::protocol::{V1Event, V1Toxic, V1Toxics};
 use disposables::util::try_use;
 
 
@@ -82,5 +82,117 @@ fn custom_file() {
         "Unexpected response: {response}");
 }
 
+#[test]
+fn tunnelled_connection() {
+    drop(env_logger::try_init());
+
+    log::info!("Creating container...");
+    let mut container = ContainerParams::new("docker.io/nginx:alpine")
+        .wait_for_port(80)
+        .create().unwrap();
+
+    log::info!("Container created, waiting...");
+    let event = container.wait();
+    assert!(matches!(event, Ok(V1Event::Ready)),
+        "Container start failed: {event:?}, logs: {}", container.logs().unwrap());
+
+    log::info!("Container ready");
+    let mut conn = container.connect(80).unwrap();
+    log::info!("Connected");
+
+    write!(conn, "GET / HTTP/1.0\nHost: localhost\n\n").unwrap();
+    let mut response_buf = Vec::<u8>::new();
+    conn.read_to_end(&mut response_buf).unwrap();
+    let response = String::from_utf8(response_buf).unwrap();
+    log::info!("Received response {response}");
+    assert_eq!(response.split("\r\n").next().unwrap(), "HTTP/1.1 200 OK",
+        "Unexpected response: {response}");
+
+    assert!(container.connect(81).is_err(),
+        "Connecting to a closed port should fail");
+}
+
+#[test]
+fn proxied_port_with_toxics() {
+    drop(env_logger::try_init());
+
+    log::info!("Creating container...");
+    let mut container = ContainerParams::new("docker.io/nginx:alpine")
+        .proxy_port(80)
+        .wait_for_port(80)
+        .create().unwrap();
+
+    log::info!("Container created, waiting...");
+    let event = container.wait();
+    assert!(matches!(event, Ok(V1Event::Ready)),
+        "Container start failed: {event:?}, logs: {}", container.logs().unwrap());
+
+    let request = |container: &disposables::Container| {
+        let mut conn = try_use(container.port(80).unwrap(), TcpStream::connect)
+            .unwrap();
+        write!(conn, "GET / HTTP/1.0\nHost: localhost\n\n")?;
+        let mut response_buf = Vec::<u8>::new();
+        conn.read_to_end(&mut response_buf)?;
+        Ok::<_, std::io::Error>(response_buf)
+    };
+
+    log::info!("Container ready");
+    assert!(!request(&container).unwrap().is_empty());
+
+    container.set_toxics(80, V1Toxics {
+        upstream: vec![],
+        downstream: vec![V1Toxic::ResetPeer],
+    }).unwrap();
+    let response = request(&container);
+    assert!(response.as_ref().map_or(true, Vec::is_empty),
+        "Connection should have been reset: {response:?}");
+
+    container.set_toxics(80, V1Toxics::default()).unwrap();
+    assert!(!request(&container).unwrap().is_empty());
+
+    assert!(container.set_toxics(81, V1Toxics::default()).is_err(),
+        "Setting toxics on a port that is not proxied should fail");
+}
+
+#[test]
+fn port_used_by_proxy_is_rejected() {
+    //The first proxy listens on port 5
+    let res = ContainerParams::new("docker.io/nginx:alpine")
+        .proxy_port(80)
+        .wait_for_port(5)
+        .create();
+    assert!(matches!(res, Err(Error::InvalidParams(_))),
+        "Unexpected result: {:?}", res.map(|c| c.id().to_owned()));
+}
+
 //TODO: Delayed startup
 
+
+#[test]
+fn tls_handshake_is_waited_for() {
+    drop(env_logger::try_init());
+
+    let conf = "server {
+        listen 443 ssl;
+        ssl_certificate /etc/nginx/localhost.crt;
+        ssl_certificate_key /etc/nginx/localhost.key;
+    }";
+    let mut container = ContainerParams::new("docker.io/nginx:alpine")
+        .file("/etc/nginx/conf.d/default.conf", conf)
+        .file("/etc/nginx/localhost.crt",
+            include_str!("../data/localhost.crt"))
+        .file("/etc/nginx/localhost.key",
+            include_str!("../data/localhost.key"))
+        .wait_for_tls_with(443, Some("localhost"), None)
+        .create().unwrap();
+
+    let event = container.wait();
+    assert!(matches!(event, Ok(V1Event::Ready)),
+        "Container start failed: {event:?}, logs: {}", container.logs().unwrap());
+
+    let certificates = container.tls_certificates();
+    assert_eq!(certificates.len(), 1);
+    assert_eq!(certificates[0].port, 443);
+    assert_eq!(certificates[0].subject, "CN=localhost");
+    assert_eq!(certificates[0].sha256.len(), 64);
+}
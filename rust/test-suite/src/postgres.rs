@@ -45,3 +45,25 @@ async fn normal_server() {
     sqlx::query("CREATE TABLE test(id INTEGER);")
         .execute(&pool).await.unwrap();
 }
+
+#[tokio::test]
+async fn unix_socket_server() {
+    drop(env_logger::try_init());
+
+    let socket = "/var/run/postgresql/.s.PGSQL.5432";
+    let mut container = ContainerParams::new("docker.io/postgres:alpine")
+        .env("POSTGRES_PASSWORD", "postgres")
+        .wait_for_unix_socket(socket)
+        .create().unwrap();
+
+    assert!(matches!(container.wait(), Ok(V1Event::Ready)),
+        "Container start failed, Logs: {}", container.logs().unwrap());
+
+    let forward = container.forward_unix(socket).unwrap();
+    let addr = format!("postgres://postgres:postgres@{}/postgres",
+        forward.addr());
+    let pool = PgPoolOptions::new().connect(&addr).await.unwrap();
+
+    sqlx::query("CREATE TABLE test(id INTEGER);")
+        .execute(&pool).await.unwrap();
+}
@@ -0,0 +1,55 @@
+/*
+ * Copyright 2024 Akash Rawal
+ *
+ * This file is part of Disposables.
+ *
+ * Disposables is free software: you can redistribute it and/or modify it under 
+ * the terms of the GNU General Public License as published by the 
+ * Free Software Foundation, either version 3 of the License, or 
+ * (at your option) any later version.
+ * 
+ * Disposables is distributed in the hope that it will be useful, 
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of 
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. 
+ * See the GNU General Public License for more details.
+ * 
+ * You should have received a copy of the GNU General Public License 
+ * along with Disposables. If not, see <https://www.gnu.org/licenses/>. 
+ */
+
+use std::io::{Read, Write};
+use std::net::TcpListener;
+
+use disposables::args::Args;
+use disposables::container::ContainerParams;
+use disposables::protocol::V1Event;
+
+#[test]
+fn reverse_tunnel() {
+    drop(env_logger::try_init());
+
+    //Tiny HTTP server on the host
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let host_addr = listener.local_addr().unwrap().to_string();
+    std::thread::spawn(move || {
+        for conn in listener.incoming() {
+            let mut conn = conn.unwrap();
+            let mut request_buf = [0_u8; 1024];
+            let _ = conn.read(&mut request_buf).unwrap();
+            write!(conn, "HTTP/1.0 200 OK\r\n\r\nhello from host").unwrap();
+        }
+    });
+
+    log::info!("Creating container...");
+    let mut container = ContainerParams::new("docker.io/alpine")
+        .entrypoint(Args::from(["sh", "-c",
+            "wget -q -O - http://127.0.0.1:8080/ && echo && sleep 60"]))
+        .reverse_tunnel(8080, host_addr)
+        .wait_for_stdout("hello from host")
+        .create().unwrap();
+
+    log::info!("Container created, waiting...");
+    let event = container.wait();
+    assert!(matches!(event, Ok(V1Event::Ready)),
+        "Container start failed: {event:?}, logs: {}", container.logs().unwrap());
+}